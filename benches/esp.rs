@@ -0,0 +1,319 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gpconnect::libs::esp::{EncAlgo, HmacAlgo, ESP};
+
+/// 原地封装之前的 `ESPPacket::encrypt` / `to_bytes` 路径, 照搬到这里作为基准:
+/// 每个包都用 libaes 重新展开 AES 密钥, 重新创建 HMAC / GCM 上下文,
+/// 并为载荷, 尾部, 密文和 ICV 分别分配 Vec
+mod legacy {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+    use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+    use gpconnect::libs::esp::{EncAlgo, HmacAlgo};
+    use gpconnect::libs::replay::ReplayWindow;
+    use hmac::{Hmac, Mac};
+    use libaes::Cipher;
+    use rand::RngCore;
+    use sha1::Sha1;
+    use sha2::{Sha256, Sha384, Sha512};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    pub struct Esp {
+        next_seq: AtomicU64,
+        spi: u32,
+        enc_algo: EncAlgo,
+        hmac: Option<HmacAlgo>,
+        enc_key: Vec<u8>,
+        mac_key: Vec<u8>,
+        replay: Mutex<ReplayWindow>,
+    }
+
+    struct Packet {
+        spi: u32,
+        seq: u32,
+        iv: Vec<u8>,
+        data: Vec<u8>,
+        icv: Vec<u8>,
+    }
+
+    impl Packet {
+        fn header(&self) -> [u8; 8] {
+            let mut header = [0u8; 8];
+            header[..4].copy_from_slice(&self.spi.to_be_bytes());
+            header[4..].copy_from_slice(&self.seq.to_be_bytes());
+            header
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            let mut packet = Vec::new();
+            packet.extend_from_slice(&self.spi.to_be_bytes());
+            packet.extend_from_slice(&self.seq.to_be_bytes());
+            packet.extend_from_slice(&self.iv);
+            packet.extend_from_slice(&self.data);
+            packet.extend_from_slice(&self.icv);
+            packet
+        }
+    }
+
+    impl Esp {
+        pub fn new(
+            seq: u32,
+            spi: u32,
+            enc_algo: EncAlgo,
+            hmac: Option<HmacAlgo>,
+            enc_key: &[u8],
+            mac_key: &[u8],
+        ) -> Self {
+            Esp {
+                next_seq: AtomicU64::new(seq as u64),
+                spi,
+                enc_algo,
+                hmac,
+                enc_key: enc_key.to_vec(),
+                mac_key: mac_key.to_vec(),
+                replay: Mutex::new(ReplayWindow::default()),
+            }
+        }
+
+        fn iv_len(&self) -> usize {
+            match self.enc_algo.is_aead() {
+                true => 8,
+                false => 16,
+            }
+        }
+
+        fn block_size(&self) -> usize {
+            match self.enc_algo.is_aead() {
+                true => 4,
+                false => 16,
+            }
+        }
+
+        fn icv_len(&self) -> usize {
+            self.hmac.map_or(16, |h| h.icv_len())
+        }
+
+        pub fn encapsulate(&self, payload: &[u8]) -> Vec<u8> {
+            let next_header = match payload[0] >> 4 {
+                6 => 41,
+                _ => 4,
+            };
+            let seq = self.next_seq.fetch_add(1, Ordering::Relaxed) as u32;
+            let mut iv = vec![0u8; self.iv_len()];
+            match self.enc_algo.is_aead() {
+                true => iv.copy_from_slice(&(seq as u64).to_be_bytes()),
+                false => rand::thread_rng().fill_bytes(&mut iv),
+            }
+            let mut packet = Packet {
+                spi: self.spi,
+                seq,
+                iv,
+                data: payload.to_vec(),
+                icv: Vec::new(),
+            };
+
+            let block_size = self.block_size();
+            let pad_len = (block_size - (packet.data.len() + 2) % block_size) % block_size;
+            packet
+                .data
+                .extend((1..=pad_len as u8).chain([pad_len as u8, next_header]));
+            let (data, icv) = self.seal(&packet.header(), &packet.iv, &packet.data);
+            packet.data = data;
+            packet.icv = icv;
+            packet.to_bytes()
+        }
+
+        pub fn decapsulate(&self, bytes: &[u8]) -> Vec<u8> {
+            let (header, rest) = bytes.split_at(8);
+            let (iv, rest) = rest.split_at(self.iv_len());
+            let (data, icv) = rest.split_at(rest.len() - self.icv_len());
+            let packet = Packet {
+                spi: u32::from_be_bytes(header[..4].try_into().unwrap()),
+                seq: u32::from_be_bytes(header[4..].try_into().unwrap()),
+                iv: iv.to_vec(),
+                data: data.to_vec(),
+                icv: icv.to_vec(),
+            };
+            self.replay.lock().unwrap().check(packet.seq).unwrap();
+            let mut data = self
+                .open(&packet.header(), &packet.iv, &packet.data, &packet.icv)
+                .expect("authentic packet");
+            let pad_len = data[data.len() - 2] as usize;
+            data.truncate(data.len() - pad_len - 2);
+            self.replay.lock().unwrap().accept(packet.seq).unwrap();
+            data
+        }
+
+        fn seal(&self, header: &[u8; 8], iv: &[u8], plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            match self.enc_algo.is_aead() {
+                true => {
+                    let mut sealed = self.gcm(iv, header, plaintext, true).unwrap();
+                    let icv = sealed.split_off(sealed.len() - 16);
+                    (sealed, icv)
+                }
+                false => {
+                    let ciphertext = self.cbc().cbc_encrypt(iv, plaintext);
+                    let icv = self.icv(header, iv, &ciphertext);
+                    (ciphertext, icv)
+                }
+            }
+        }
+
+        fn open(
+            &self,
+            header: &[u8; 8],
+            iv: &[u8],
+            ciphertext: &[u8],
+            icv: &[u8],
+        ) -> Option<Vec<u8>> {
+            match self.enc_algo.is_aead() {
+                true => self.gcm(iv, header, &[ciphertext, icv].concat(), false),
+                false => {
+                    let expected = self.icv(header, iv, ciphertext);
+                    let diff = expected
+                        .iter()
+                        .zip(icv)
+                        .fold(expected.len() ^ icv.len(), |acc, (a, b)| {
+                            acc | (a ^ b) as usize
+                        });
+                    (diff == 0).then(|| self.cbc().cbc_decrypt(iv, ciphertext))
+                }
+            }
+        }
+
+        fn cbc(&self) -> Cipher {
+            let mut cipher = match self.enc_key.len() {
+                16 => Cipher::new_128(self.enc_key[..].try_into().unwrap()),
+                _ => Cipher::new_256(self.enc_key[..].try_into().unwrap()),
+            };
+            cipher.set_auto_padding(false);
+            cipher
+        }
+
+        fn gcm(&self, iv: &[u8], aad: &[u8], msg: &[u8], seal: bool) -> Option<Vec<u8>> {
+            let (key, salt) = self.enc_key.split_at(self.enc_key.len() - 4);
+            let nonce = [salt, iv].concat();
+            let nonce = Nonce::from_slice(&nonce);
+            let payload = Payload { msg, aad };
+            match (key.len(), seal) {
+                (16, true) => Aes128Gcm::new_from_slice(key)
+                    .ok()?
+                    .encrypt(nonce, payload)
+                    .ok(),
+                (16, false) => Aes128Gcm::new_from_slice(key)
+                    .ok()?
+                    .decrypt(nonce, payload)
+                    .ok(),
+                (_, true) => Aes256Gcm::new_from_slice(key)
+                    .ok()?
+                    .encrypt(nonce, payload)
+                    .ok(),
+                (_, false) => Aes256Gcm::new_from_slice(key)
+                    .ok()?
+                    .decrypt(nonce, payload)
+                    .ok(),
+            }
+        }
+
+        fn icv(&self, header: &[u8], iv: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+            let hmac = self.hmac.expect("CBC needs an hmac algorithm");
+            let parts = [header, iv, ciphertext];
+            let mut icv = match hmac {
+                HmacAlgo::Sha1 => mac::<Hmac<Sha1>>(&self.mac_key, &parts),
+                HmacAlgo::Sha256 => mac::<Hmac<Sha256>>(&self.mac_key, &parts),
+                HmacAlgo::Sha384 => mac::<Hmac<Sha384>>(&self.mac_key, &parts),
+                HmacAlgo::Sha512 => mac::<Hmac<Sha512>>(&self.mac_key, &parts),
+            };
+            icv.truncate(hmac.icv_len());
+            icv
+        }
+    }
+
+    fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        let mut mac = <M as hmac::digest::KeyInit>::new_from_slice(key).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// 每次迭代封装一个包再在对端解开, 吞吐量即每秒处理的包数.
+/// `legacy` 是引入原地接口之前的实现, 与 `vec` / `in-place` 在同一次运行中对比
+fn round_trip(c: &mut Criterion) {
+    let suites = [
+        ("aes-128-cbc-sha1", EncAlgo::Aes128Cbc, Some(HmacAlgo::Sha1)),
+        (
+            "aes-256-cbc-sha256",
+            EncAlgo::Aes256Cbc,
+            Some(HmacAlgo::Sha256),
+        ),
+        ("aes-128-gcm", EncAlgo::Aes128Gcm, None),
+    ];
+    let mut group = c.benchmark_group("esp");
+    group.throughput(Throughput::Elements(1));
+
+    for (name, algo, hmac) in suites {
+        let enc_key = vec![0x24u8; algo.key_len()];
+        let mac_key = vec![0x42u8; hmac.map_or(0, |h| h.key_len())];
+        let new = || ESP::new(1, 0x6f77893a, algo, hmac, &enc_key, &mac_key).unwrap();
+
+        for size in [64usize, 1400] {
+            let mut packet = vec![0u8; size];
+            packet[0] = 0x45;
+
+            // 原地接口之前的实现
+            let (outbound, inbound) = (
+                legacy::Esp::new(1, 0x6f77893a, algo, hmac, &enc_key, &mac_key),
+                legacy::Esp::new(1, 0x6f77893a, algo, hmac, &enc_key, &mac_key),
+            );
+            // 两种实现的包互通, 保证比较的是同样的工作
+            assert_eq!(
+                new().decapsulate(&outbound.encapsulate(&packet)).unwrap(),
+                packet
+            );
+            assert_eq!(
+                inbound.decapsulate(&new().encapsulate(&packet).unwrap()),
+                packet
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}/legacy", name), size),
+                &packet,
+                |b, packet| {
+                    b.iter(|| {
+                        let wire = outbound.encapsulate(packet);
+                        inbound.decapsulate(&wire)
+                    })
+                },
+            );
+
+            // 分配内存的接口: 每个包都复制载荷并创建新的 Vec
+            let (outbound, inbound) = (new(), new());
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}/vec", name), size),
+                &packet,
+                |b, packet| {
+                    b.iter(|| {
+                        let wire = outbound.encapsulate(packet).unwrap();
+                        inbound.decapsulate(&wire).unwrap()
+                    })
+                },
+            );
+
+            // 原地接口: 同一个缓冲区反复使用, 解密后内层包正好回到 headroom 之后
+            let (outbound, inbound) = (new(), new());
+            let headroom = outbound.headroom();
+            let mut buf = vec![0u8; headroom + size + outbound.tailroom()];
+            buf[headroom..headroom + size].copy_from_slice(&packet);
+            group.bench_function(BenchmarkId::new(format!("{}/in-place", name), size), |b| {
+                b.iter(|| {
+                    let len = outbound.encapsulate_in_place(&mut buf, size).unwrap();
+                    inbound.decapsulate_in_place(&mut buf[..len]).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_trip);
+criterion_main!(benches);
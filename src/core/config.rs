@@ -1,5 +1,10 @@
+use crate::gp::getconfig::GatewayConfig;
 use crate::libs::esp::ESP;
+
+/// 连接网关所需的全部配置, 由 getconfig 响应生成
+#[derive(Debug)]
 pub struct Config {
-    espin: ESP,
-    espout: ESP,
+    pub espin: ESP,
+    pub espout: ESP,
+    pub gateway: GatewayConfig,
 }
@@ -0,0 +1,48 @@
+/// 登录门户或网关时使用的凭据
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub secret: Secret,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Secret {
+    Password(String),
+    /// SAML 认证完成后得到的 prelogin-cookie
+    PreloginCookie(String),
+    /// 门户 getconfig 下发的 portal-userauthcookie
+    PortalUserAuthCookie(String),
+    /// 门户 getconfig 下发的 portal-prelogonuserauthcookie
+    PortalPrelogonUserAuthCookie(String),
+}
+
+impl Credentials {
+    pub fn new(username: &str, secret: Secret) -> Self {
+        Credentials {
+            username: username.to_string(),
+            secret,
+        }
+    }
+
+    /// 生成提交给 getconfig.esp / login.esp 的表单字段, 未使用的字段保留为空
+    pub fn form_fields(&self) -> Vec<(&'static str, String)> {
+        let mut passwd = String::new();
+        let mut prelogin_cookie = String::new();
+        let mut userauthcookie = String::new();
+        let mut prelogonuserauthcookie = String::new();
+        match &self.secret {
+            Secret::Password(v) => passwd = v.clone(),
+            Secret::PreloginCookie(v) => prelogin_cookie = v.clone(),
+            Secret::PortalUserAuthCookie(v) => userauthcookie = v.clone(),
+            Secret::PortalPrelogonUserAuthCookie(v) => prelogonuserauthcookie = v.clone(),
+        }
+
+        vec![
+            ("user", self.username.clone()),
+            ("passwd", passwd),
+            ("prelogin-cookie", prelogin_cookie),
+            ("portal-userauthcookie", userauthcookie),
+            ("portal-prelogonuserauthcookie", prelogonuserauthcookie),
+        ]
+    }
+}
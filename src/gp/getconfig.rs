@@ -1,93 +1,783 @@
+use super::identity::ClientIdentity;
+use super::login::AuthCookie;
 use crate::core::config::Config;
+use crate::libs::esp::{EncAlgo, HmacAlgo, ESP};
+use ipnet::{Ipv4Net, Ipv6Net};
 use reqwest::cookie::Jar;
 use reqwest::{Client, Url};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_xml_rs::from_str;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// getconfig.esp 的原始 XML 响应, 所有字段都按字符串读取, 由 `parse_config` 逐字段校验
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct Response {
+    status: Option<String>,
+    error: Option<String>,
+    #[serde(rename = "need-tunnel")]
+    need_tunnel: Option<String>,
+    #[serde(rename = "ssl-tunnel-url")]
+    ssl_tunnel_url: Option<String>,
+    portal: Option<String>,
+    user: Option<String>,
+    quarantine: Option<String>,
+    lifetime: Option<String>,
+    timeout: Option<String>,
+    #[serde(rename = "disconnect-on-idle")]
+    disconnect_on_idle: Option<String>,
+    #[serde(rename = "bw-c2s")]
+    bw_c2s: Option<String>,
+    #[serde(rename = "bw-s2c")]
+    bw_s2c: Option<String>,
     #[serde(rename = "gw-address")]
-    gw_address: String,
+    gw_address: Option<String>,
+    #[serde(rename = "ipv6-connection")]
+    ipv6_connection: Option<String>,
     #[serde(rename = "ip-address")]
-    ip_address: String,
-    ipsec: Ipsec,
+    ip_address: Option<String>,
+    netmask: Option<String>,
+    #[serde(rename = "ip-address-v6")]
+    ip_address_v6: Option<String>,
+    dns: Option<Members>,
+    #[serde(rename = "dns-v6")]
+    dns_v6: Option<Members>,
+    wins: Option<Members>,
+    #[serde(rename = "dns-suffix")]
+    dns_suffix: Option<Members>,
+    #[serde(rename = "default-gateway")]
+    default_gateway: Option<String>,
+    #[serde(rename = "default-gateway-v6")]
+    default_gateway_v6: Option<String>,
+    mtu: Option<String>,
+    #[serde(rename = "no-direct-access-to-local-network")]
+    no_direct_access_to_local_network: Option<String>,
+    #[serde(rename = "access-routes")]
+    access_routes: Option<Members>,
+    #[serde(rename = "access-routes-v6")]
+    access_routes_v6: Option<Members>,
+    #[serde(rename = "exclude-access-routes")]
+    exclude_access_routes: Option<Members>,
+    #[serde(rename = "exclude-access-routes-v6")]
+    exclude_access_routes_v6: Option<Members>,
+    ipsec: Option<Ipsec>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Members {
+    member: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct Ipsec {
+    #[serde(rename = "udp-port")]
+    udp_port: Option<String>,
+    #[serde(rename = "ipsec-mode")]
+    ipsec_mode: Option<String>,
+    #[serde(rename = "enc-algo")]
+    enc_algo: Option<String>,
+    #[serde(rename = "hmac-algo")]
+    hmac_algo: Option<String>,
     #[serde(rename = "c2s-spi")]
-    c2s_spi: String,
+    c2s_spi: Option<String>,
     #[serde(rename = "s2c-spi")]
-    s2c_spi: String,
+    s2c_spi: Option<String>,
     #[serde(rename = "akey-s2c")]
-    akey_s2c: Key,
+    akey_s2c: Option<Key>,
     #[serde(rename = "ekey-s2c")]
-    ekey_s2c: Key,
+    ekey_s2c: Option<Key>,
     #[serde(rename = "akey-c2s")]
-    akey_c2s: Key,
+    akey_c2s: Option<Key>,
     #[serde(rename = "ekey-c2s")]
-    ekey_c2s: Key,
+    ekey_c2s: Option<Key>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct Key {
-    bits: String,
-    val: String,
-}
-
-async fn get_config(auth_cookie: &str, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
-    let url = "https://47.100.9.56/ssl-vpn/getconfig.esp";
-
-    let jar = Jar::default();
-    jar.add_cookie_str("CLIENTOS=V2luZG93cw==", &url.parse::<Url>().unwrap());
-    let client = Client::builder()
-        .cookie_provider(Arc::new(jar))
-        .danger_accept_invalid_certs(true)
-        .build()
-        .unwrap();
-
-    let mut data = HashMap::new();
-    data.insert("user", "ling.pcheng@fujitsu.com");
-    data.insert("addr1", "172.26.112.1/20");
-    data.insert("addr2", "172.16.200.227/22");
-    data.insert("preferred-ip", "");
-    data.insert("preferred-ipv6", "");
-    data.insert("portal", "GP-GW-SHAP-N");
-    data.insert("authcookie", auth_cookie);
-    data.insert("client-type", "1");
-    data.insert("exclude-video-support", "yes");
-    data.insert("os-version", "Microsoft Windows 11 Pro , 64-bit");
-    data.insert("app-version", "6.0.8-601");
-    data.insert("protocol-version", "p1");
-    data.insert("clientos", "Windows");
-    data.insert("ipv6-support", "yes");
-    data.insert("internal", "no");
-    data.insert("client-ip", "172.16.200.227");
-    data.insert("client-ipv6", "");
-    data.insert("serialno", "DZVQPM3");
-    data.insert("mac-addr", "f2-e3-f7-1c-85-b3");
-    data.insert("joined-domain", "g08.fujitsu.local");
-    data.insert("enc-algo", "aes-256-gcm,aes-128-gcm,aes-128-cbc,");
-    data.insert("hmac-algo", "sha1,");
-
-    match client.post(url).form(&data).send().await {
-        Ok(response) => match response.text().await {
-            Ok(text) => {
-                let response: Response = from_str(&text)?;
-                Ok(())
-            }
-            Err(e) => Err(Box::new(e)),
-        },
-        Err(e) => Err(Box::new(e)),
+    bits: Option<String>,
+    val: Option<String>,
+}
+
+/// getconfig 解析错误, 精确到出错的字段
+#[derive(Debug)]
+pub enum ConfigError {
+    Xml(serde_xml_rs::Error),
+    Status(String),
+    Missing(&'static str),
+    Invalid {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Xml(e) => write!(f, "malformed getconfig response: {}", e),
+            ConfigError::Status(msg) => write!(f, "gateway returned error: {}", msg),
+            ConfigError::Missing(field) => write!(f, "missing field <{}>", field),
+            ConfigError::Invalid {
+                field,
+                value,
+                reason,
+            } => write!(f, "invalid <{}> value {:?}: {}", field, value, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_xml_rs::Error> for ConfigError {
+    fn from(e: serde_xml_rs::Error) -> Self {
+        ConfigError::Xml(e)
+    }
+}
+
+/// 网关 getconfig 响应的强类型模型
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    pub need_tunnel: bool,
+    pub ssl_tunnel_url: Option<String>,
+    pub portal: String,
+    pub user: String,
+    pub quarantine: bool,
+    pub lifetime: Duration,
+    pub timeout: Duration,
+    pub disconnect_on_idle: Duration,
+    pub bw_c2s: u32,
+    pub bw_s2c: u32,
+    pub gw_address: IpAddr,
+    pub ipv6_connection: bool,
+    pub ip_address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub ip_address_v6: Option<Ipv6Addr>,
+    pub default_gateway: Option<Ipv4Addr>,
+    pub default_gateway_v6: Option<Ipv6Addr>,
+    pub dns: Vec<IpAddr>,
+    pub dns_v6: Vec<IpAddr>,
+    pub wins: Vec<IpAddr>,
+    pub dns_suffix: Vec<String>,
+    /// None 表示网关未指定 MTU, `<mtu>0</mtu>` 也解析为 None
+    pub mtu: Option<u16>,
+    pub no_direct_access_to_local_network: bool,
+    pub access_routes: Vec<Ipv4Net>,
+    pub access_routes_v6: Vec<Ipv6Net>,
+    pub exclude_access_routes: Vec<Ipv4Net>,
+    pub exclude_access_routes_v6: Vec<Ipv6Net>,
+    pub ipsec: IpsecConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct IpsecConfig {
+    pub udp_port: u16,
+    pub ipsec_mode: String,
+    pub enc_algo: EncAlgo,
+    /// AES-GCM 自带完整性校验, 此时为 None 且 akey 为空
+    pub hmac_algo: Option<HmacAlgo>,
+    pub c2s_spi: u32,
+    pub s2c_spi: u32,
+    pub akey_s2c: Vec<u8>,
+    pub ekey_s2c: Vec<u8>,
+    pub akey_c2s: Vec<u8>,
+    pub ekey_c2s: Vec<u8>,
+}
+
+/// getconfig 请求中通告的加密算法, 按优先顺序排列, 都必须能由 `new_suite` 创建
+const ENC_ALGOS: &str = "aes-256-gcm,aes-128-gcm,aes-256-cbc,aes-128-cbc,";
+/// getconfig 请求中通告的认证算法, 只用于 CBC
+const HMAC_ALGOS: &str = "sha1,sha256,sha384,sha512,";
+
+/// `cookies` 是创建 `client` 时的 `HttpOptions::cookies`, prelogin 和 login 的会话 cookie 也在其中
+pub async fn get_config(
+    client: &Client,
+    cookies: &Jar,
+    gateway: &str,
+    cookie: &AuthCookie,
+    identity: &ClientIdentity,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    let url = format!("https://{}/ssl-vpn/getconfig.esp", gateway);
+    request_config(client, cookies, &url, cookie, identity).await
+}
+
+async fn request_config(
+    client: &Client,
+    cookies: &Jar,
+    url: &str,
+    cookie: &AuthCookie,
+    identity: &ClientIdentity,
+) -> Result<Config, Box<dyn std::error::Error>> {
+    // 直接设置 Cookie 头会让 reqwest 不再附带 cookie 存储中的会话 cookie
+    cookies.add_cookie_str(&identity.clientos_cookie(), &url.parse::<Url>()?);
+
+    let mut data = identity.host_fields();
+    let fields = [
+        ("user", cookie.user.clone()),
+        (
+            "preferred-ip",
+            cookie.preferred_ip.clone().unwrap_or_default(),
+        ),
+        (
+            "preferred-ipv6",
+            cookie.preferred_ipv6.clone().unwrap_or_default(),
+        ),
+        ("portal", cookie.portal.clone()),
+        ("authcookie", cookie.authcookie.clone()),
+        ("client-type", "1".to_string()),
+        ("exclude-video-support", "yes".to_string()),
+        ("protocol-version", "p1".to_string()),
+        ("ipv6-support", "yes".to_string()),
+        ("internal", "no".to_string()),
+        ("enc-algo", ENC_ALGOS.to_string()),
+        ("hmac-algo", HMAC_ALGOS.to_string()),
+    ];
+    data.extend(fields.map(|(name, value)| (name.to_string(), value)));
+
+    let response = client.post(url).form(&data).send().await?;
+    let text = response.text().await?;
+    Ok(parse_response(&text)?)
+}
+
+/// 解析 getconfig.esp 返回的 XML
+pub fn parse_response(xml: &str) -> Result<Config, ConfigError> {
+    let response: Response = from_str(xml)?;
+    parse_config(response)
+}
+
+fn parse_config(config: Response) -> Result<Config, ConfigError> {
+    let gateway = parse_gateway(&config)?;
+    let ipsec = &gateway.ipsec;
+
+    // 出站使用 c2s 密钥, 入站使用 s2c 密钥
+    let espout = ESP::new(
+        1u32,
+        ipsec.c2s_spi,
+        ipsec.enc_algo,
+        ipsec.hmac_algo,
+        &ipsec.ekey_c2s,
+        &ipsec.akey_c2s,
+    )
+    .map_err(|e| invalid("ipsec/ekey-c2s", &hex::encode(&ipsec.ekey_c2s), e))?;
+    let espin = ESP::new(
+        1u32,
+        ipsec.s2c_spi,
+        ipsec.enc_algo,
+        ipsec.hmac_algo,
+        &ipsec.ekey_s2c,
+        &ipsec.akey_s2c,
+    )
+    .map_err(|e| invalid("ipsec/ekey-s2c", &hex::encode(&ipsec.ekey_s2c), e))?;
+
+    Ok(Config {
+        espin,
+        espout,
+        gateway,
+    })
+}
+
+fn parse_gateway(r: &Response) -> Result<GatewayConfig, ConfigError> {
+    if let Some(status) = r.status.as_deref() {
+        if status != "success" {
+            let msg = r.error.clone().unwrap_or_else(|| status.to_string());
+            return Err(ConfigError::Status(msg.trim().to_string()));
+        }
+    }
+
+    let mtu: u16 = optional("mtu", &r.mtu)?.unwrap_or(0);
+
+    Ok(GatewayConfig {
+        need_tunnel: yes_no("need-tunnel", &r.need_tunnel)?,
+        ssl_tunnel_url: text(&r.ssl_tunnel_url).map(str::to_string),
+        portal: required("portal", &r.portal)?.to_string(),
+        user: required("user", &r.user)?.to_string(),
+        quarantine: yes_no("quarantine", &r.quarantine)?,
+        lifetime: seconds("lifetime", &r.lifetime)?,
+        timeout: seconds("timeout", &r.timeout)?,
+        disconnect_on_idle: seconds("disconnect-on-idle", &r.disconnect_on_idle)?,
+        bw_c2s: optional("bw-c2s", &r.bw_c2s)?.unwrap_or(0),
+        bw_s2c: optional("bw-s2c", &r.bw_s2c)?.unwrap_or(0),
+        gw_address: parse("gw-address", &r.gw_address)?,
+        ipv6_connection: yes_no("ipv6-connection", &r.ipv6_connection)?,
+        ip_address: parse("ip-address", &r.ip_address)?,
+        netmask: optional("netmask", &r.netmask)?.unwrap_or(Ipv4Addr::BROADCAST),
+        ip_address_v6: optional("ip-address-v6", &r.ip_address_v6)?,
+        default_gateway: optional("default-gateway", &r.default_gateway)?,
+        default_gateway_v6: optional("default-gateway-v6", &r.default_gateway_v6)?,
+        dns: members("dns", &r.dns)?,
+        dns_v6: members("dns-v6", &r.dns_v6)?,
+        wins: members("wins", &r.wins)?,
+        dns_suffix: members("dns-suffix", &r.dns_suffix)?,
+        mtu: if mtu == 0 { None } else { Some(mtu) },
+        no_direct_access_to_local_network: yes_no(
+            "no-direct-access-to-local-network",
+            &r.no_direct_access_to_local_network,
+        )?,
+        access_routes: routes("access-routes", &r.access_routes)?,
+        access_routes_v6: routes("access-routes-v6", &r.access_routes_v6)?,
+        exclude_access_routes: routes("exclude-access-routes", &r.exclude_access_routes)?,
+        exclude_access_routes_v6: routes("exclude-access-routes-v6", &r.exclude_access_routes_v6)?,
+        ipsec: parse_ipsec(r.ipsec.as_ref().ok_or(ConfigError::Missing("ipsec"))?)?,
+    })
+}
+
+fn parse_ipsec(r: &Ipsec) -> Result<IpsecConfig, ConfigError> {
+    let enc_algo: EncAlgo = parse("ipsec/enc-algo", &r.enc_algo)?;
+    let hmac_algo: Option<HmacAlgo> = match enc_algo.is_aead() {
+        true => None,
+        false => Some(parse("ipsec/hmac-algo", &r.hmac_algo)?),
+    };
+    let ekey_len = enc_algo.key_len();
+    let akey = |field, value| match hmac_algo {
+        Some(algo) => key(field, value, algo.key_len()),
+        None => Ok(Vec::new()),
+    };
+
+    Ok(IpsecConfig {
+        udp_port: optional("ipsec/udp-port", &r.udp_port)?.unwrap_or(4501),
+        ipsec_mode: optional("ipsec/ipsec-mode", &r.ipsec_mode)?
+            .unwrap_or_else(|| "esp-tunnel".to_string()),
+        enc_algo,
+        hmac_algo,
+        c2s_spi: spi("ipsec/c2s-spi", &r.c2s_spi)?,
+        s2c_spi: spi("ipsec/s2c-spi", &r.s2c_spi)?,
+        akey_s2c: akey("ipsec/akey-s2c", &r.akey_s2c)?,
+        ekey_s2c: key("ipsec/ekey-s2c", &r.ekey_s2c, ekey_len)?,
+        akey_c2s: akey("ipsec/akey-c2s", &r.akey_c2s)?,
+        ekey_c2s: key("ipsec/ekey-c2s", &r.ekey_c2s, ekey_len)?,
+    })
+}
+
+fn invalid(field: &'static str, value: &str, reason: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// 去除空白后的文本, 空元素视为不存在
+fn text(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+fn required<'a>(field: &'static str, value: &'a Option<String>) -> Result<&'a str, ConfigError> {
+    text(value).ok_or(ConfigError::Missing(field))
+}
+
+fn parse<T>(field: &'static str, value: &Option<String>) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = required(field, value)?;
+    value.parse().map_err(|e| invalid(field, value, e))
+}
+
+fn optional<T>(field: &'static str, value: &Option<String>) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match text(value) {
+        Some(v) => v.parse().map(Some).map_err(|e| invalid(field, v, e)),
+        None => Ok(None),
+    }
+}
+
+fn yes_no(field: &'static str, value: &Option<String>) -> Result<bool, ConfigError> {
+    match text(value) {
+        Some("yes") => Ok(true),
+        Some("no") | None => Ok(false),
+        Some(v) => Err(invalid(field, v, "expected yes or no")),
+    }
+}
+
+fn seconds(field: &'static str, value: &Option<String>) -> Result<Duration, ConfigError> {
+    Ok(Duration::from_secs(optional(field, value)?.unwrap_or(0)))
+}
+
+fn members<T>(field: &'static str, value: &Option<Members>) -> Result<Vec<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let Some(list) = value else {
+        return Ok(Vec::new());
+    };
+    list.member
+        .iter()
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(|m| m.parse().map_err(|e| invalid(field, m, e)))
+        .collect()
+}
+
+/// 路由条目允许省略前缀长度, 此时视为主机路由
+fn routes<N>(field: &'static str, value: &Option<Members>) -> Result<Vec<N>, ConfigError>
+where
+    N: FromStr,
+    N::Err: fmt::Display,
+{
+    let Some(list) = value else {
+        return Ok(Vec::new());
+    };
+    list.member
+        .iter()
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(|m| {
+            let cidr = match m.contains('/') {
+                true => m.to_string(),
+                false => match m.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(_)) => format!("{}/32", m),
+                    Ok(IpAddr::V6(_)) => format!("{}/128", m),
+                    Err(e) => return Err(invalid(field, m, e)),
+                },
+            };
+            cidr.parse().map_err(|e| invalid(field, m, e))
+        })
+        .collect()
+}
+
+fn spi(field: &'static str, value: &Option<String>) -> Result<u32, ConfigError> {
+    let value = required(field, value)?;
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u32::from_str_radix(hex, 16).map_err(|e| invalid(field, value, e))
+}
+
+fn key(
+    field: &'static str,
+    value: &Option<Key>,
+    expected_len: usize,
+) -> Result<Vec<u8>, ConfigError> {
+    let key = value.as_ref().ok_or(ConfigError::Missing(field))?;
+    let bits: usize = parse(field, &key.bits)?;
+    let val = required(field, &key.val)?;
+    let bytes = hex::decode(val).map_err(|e| invalid(field, val, e))?;
+    if bytes.len() * 8 != bits {
+        return Err(invalid(
+            field,
+            val,
+            format!("key is {} bits but <bits> says {}", bytes.len() * 8, bits),
+        ));
+    }
+    if bytes.len() != expected_len {
+        return Err(invalid(
+            field,
+            val,
+            format!("expected a {}-bit key", expected_len * 8),
+        ));
     }
+    Ok(bytes)
 }
 
-fn parse_config(config: Response) -> Result<Config, Box<dyn std::error::Error>> {
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const GETCONFIG_XML: &str = r#"
+    <response status="success">
+		<need-tunnel>yes</need-tunnel>
+		<ssl-tunnel-url>/ssl-tunnel-connect.sslvpn</ssl-tunnel-url>
+		<portal>GP-GW-SHAP-N</portal>
+		<user>ling.pcheng@fujitsu.com</user>
+		<quarantine>no</quarantine>
+		<lifetime>2592000</lifetime>
+		<timeout>10800</timeout>
+		<disconnect-on-idle>10800</disconnect-on-idle>
+		<bw-c2s>1000</bw-c2s>
+		<bw-s2c>1000</bw-s2c>
+		<gw-address>10.193.33.1</gw-address>
+		<ipv6-connection>no</ipv6-connection>
+		<ip-address>10.193.129.116</ip-address>
+		<netmask>255.255.255.255</netmask>
+		<ip-address-preferred>yes</ip-address-preferred>
+		<ip-address-v6>fc00::1abb</ip-address-v6>
+		<ip-address-v6-preferred>yes</ip-address-v6-preferred>
+		<dns-v6>
+			<member>10.12.255.254</member>
+		</dns-v6>
+		<dns>
+			<member>10.12.255.254</member>
+		</dns>
+		<wins>
+		</wins>
+		<dns-suffix>
+		</dns-suffix>
+		<default-gateway>10.193.129.116</default-gateway>
+		<default-gateway-v6>fc00::1abb</default-gateway-v6>
+		<mtu>0</mtu>
+		<no-direct-access-to-local-network>no</no-direct-access-to-local-network>
+		<access-routes>
+			<member>0.0.0.0/0</member>
+			<member>10.12.255.254/32</member>
+		</access-routes>
+		<access-routes-v6>
+			<member>::/0</member>
+		</access-routes-v6>
+		<exclude-access-routes>
+		</exclude-access-routes>
+		<exclude-access-routes-v6>
+		</exclude-access-routes-v6>
+		<ipsec>
+			<udp-port>4501</udp-port>
+			<ipsec-mode>esp-tunnel</ipsec-mode>
+			<enc-algo>aes-128-cbc</enc-algo>
+			<hmac-algo>sha1</hmac-algo>
+			<c2s-spi>0x54C277B0</c2s-spi>
+			<s2c-spi>0x28E7990F</s2c-spi>
+			<akey-s2c>
+				<bits>160</bits>
+				<val>0734369faa973a05f44dd0bb19d4559f10ed41b8</val>
+			</akey-s2c>
+			<ekey-s2c>
+				<bits>128</bits>
+				<val>a76d929d37613210f60bd233f2806b32</val>
+			</ekey-s2c>
+			<akey-c2s>
+				<bits>160</bits>
+				<val>f53b81dae1db0d5754ef52edc1516be18ca749f5</val>
+			</akey-c2s>
+			<ekey-c2s>
+				<bits>128</bits>
+				<val>ce30001139e8cde103b214d7af0509e4</val>
+			</ekey-c2s>
+		</ipsec>
+	</response>
+    "#;
+
+    /// 依次响应 login 和 getconfig 两个请求, 返回 getconfig 请求的 Cookie 头
+    async fn serve_login_then_config(listener: tokio::net::TcpListener) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut cookie_header = String::new();
+        for response in [
+            "HTTP/1.1 200 OK\r\nSet-Cookie: SESSID=abc123; Path=/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                GETCONFIG_XML.len(),
+                GETCONFIG_XML
+            ),
+        ] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            if let Some(line) = request
+                .lines()
+                .find(|line| line.to_ascii_lowercase().starts_with("cookie:"))
+            {
+                cookie_header = line.to_string();
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        cookie_header
+    }
 
+    #[tokio::test]
+    async fn test_getconfig_keeps_session_cookies() {
+        use crate::gp::http::{build_client, HttpOptions};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_login_then_config(listener));
+
+        let options = HttpOptions::default();
+        let client = build_client(&options).unwrap();
+        client
+            .get(format!("{}/ssl-vpn/login.esp", base))
+            .send()
+            .await
+            .unwrap();
+        let cookie = AuthCookie {
+            authcookie: "cookie".to_string(),
+            portal: "GP-GW".to_string(),
+            user: "alice".to_string(),
+            domain: None,
+            preferred_ip: None,
+            preferred_ipv6: None,
+            connection_type: None,
+        };
+        let identity = ClientIdentity::default();
+        let url = format!("{}/ssl-vpn/getconfig.esp", base);
+        request_config(&client, &options.cookies, &url, &cookie, &identity)
+            .await
+            .unwrap();
+
+        let header = server.await.unwrap();
+        assert!(header.contains("SESSID=abc123"), "{}", header);
+        assert!(header.contains(&identity.clientos_cookie()), "{}", header);
+    }
+
+    #[test]
+    fn test_advertised_algorithms_are_supported() {
+        use crate::libs::suite::new_suite;
+
+        let list = |s: &'static str| s.split(',').filter(|a| !a.is_empty());
+        let enc: Vec<EncAlgo> = list(ENC_ALGOS).map(|a| a.parse().unwrap()).collect();
+        let hmac: Vec<HmacAlgo> = list(HMAC_ALGOS).map(|a| a.parse().unwrap()).collect();
+        assert_eq!(enc.len(), 4);
+        assert_eq!(hmac.len(), 4);
+        for &enc_algo in &enc {
+            for &hmac_algo in &hmac {
+                let enc_key = vec![1u8; enc_algo.key_len()];
+                let mac_key = vec![2u8; hmac_algo.key_len()];
+                new_suite(enc_algo, Some(hmac_algo), &enc_key, &mac_key)
+                    .unwrap_or_else(|e| panic!("{} {}: {}", enc_algo, hmac_algo, e));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let config = parse_response(GETCONFIG_XML).unwrap();
+        let gw = &config.gateway;
+
+        assert!(gw.need_tunnel);
+        assert_eq!(
+            gw.ssl_tunnel_url.as_deref(),
+            Some("/ssl-tunnel-connect.sslvpn")
+        );
+        assert_eq!(gw.portal, "GP-GW-SHAP-N");
+        assert_eq!(gw.lifetime, Duration::from_secs(2592000));
+        assert_eq!(gw.gw_address, "10.193.33.1".parse::<IpAddr>().unwrap());
+        assert_eq!(gw.ip_address, Ipv4Addr::new(10, 193, 129, 116));
+        assert_eq!(gw.netmask, Ipv4Addr::BROADCAST);
+        assert_eq!(gw.dns, vec!["10.12.255.254".parse::<IpAddr>().unwrap()]);
+        assert!(gw.wins.is_empty());
+        assert!(gw.dns_suffix.is_empty());
+        assert_eq!(gw.mtu, None);
+        assert_eq!(gw.access_routes.len(), 2);
+        assert_eq!(
+            gw.access_routes_v6,
+            vec!["::/0".parse::<Ipv6Net>().unwrap()]
+        );
+        assert!(gw.exclude_access_routes.is_empty());
+
+        assert_eq!(gw.ipsec.udp_port, 4501);
+        assert_eq!(gw.ipsec.enc_algo, EncAlgo::Aes128Cbc);
+        assert_eq!(gw.ipsec.hmac_algo, Some(HmacAlgo::Sha1));
+        assert_eq!(gw.ipsec.c2s_spi, 0x54C277B0);
+        assert_eq!(gw.ipsec.s2c_spi, 0x28E7990F);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_field() {
+        let xml = GETCONFIG_XML.replace("0x54C277B0", "0xZZ");
+        match parse_response(&xml) {
+            Err(ConfigError::Invalid { field, .. }) => assert_eq!(field, "ipsec/c2s-spi"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let xml = GETCONFIG_XML.replace("<bits>128</bits>", "<bits>256</bits>");
+        match parse_response(&xml) {
+            Err(ConfigError::Invalid { field, .. }) => assert_eq!(field, "ipsec/ekey-s2c"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let xml = GETCONFIG_XML.replace("<gw-address>10.193.33.1</gw-address>", "");
+        match parse_response(&xml) {
+            Err(ConfigError::Missing(field)) => assert_eq!(field, "gw-address"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_gcm_ipsec() {
+        let ekey = format!("<bits>288</bits>\n\t\t\t\t<val>{}</val>", "ab".repeat(36));
+        let xml = GETCONFIG_XML
+            .replace(
+                "<enc-algo>aes-128-cbc</enc-algo>",
+                "<enc-algo>aes-256-gcm</enc-algo>",
+            )
+            .replace("<hmac-algo>sha1</hmac-algo>", "")
+            .replace(
+                "<bits>128</bits>\n\t\t\t\t<val>a76d929d37613210f60bd233f2806b32</val>",
+                &ekey,
+            )
+            .replace(
+                "<bits>128</bits>\n\t\t\t\t<val>ce30001139e8cde103b214d7af0509e4</val>",
+                &ekey,
+            );
+        let config = parse_response(&xml).unwrap();
+        let ipsec = &config.gateway.ipsec;
+        assert_eq!(ipsec.enc_algo, EncAlgo::Aes256Gcm);
+        assert_eq!(ipsec.hmac_algo, None);
+        assert_eq!(ipsec.ekey_c2s.len(), 36);
+        assert_eq!(config.espout.enc_algo(), EncAlgo::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_parse_cbc_sha2_ipsec() {
+        let akey = format!("<bits>384</bits>\n\t\t\t\t<val>{}</val>", "cd".repeat(48));
+        let ekey = format!("<bits>256</bits>\n\t\t\t\t<val>{}</val>", "ab".repeat(32));
+        let xml = GETCONFIG_XML
+            .replace(
+                "<enc-algo>aes-128-cbc</enc-algo>",
+                "<enc-algo>aes-256-cbc</enc-algo>",
+            )
+            .replace(
+                "<hmac-algo>sha1</hmac-algo>",
+                "<hmac-algo>sha384</hmac-algo>",
+            )
+            .replace(
+                "<bits>160</bits>\n\t\t\t\t<val>0734369faa973a05f44dd0bb19d4559f10ed41b8</val>",
+                &akey,
+            )
+            .replace(
+                "<bits>160</bits>\n\t\t\t\t<val>f53b81dae1db0d5754ef52edc1516be18ca749f5</val>",
+                &akey,
+            )
+            .replace(
+                "<bits>128</bits>\n\t\t\t\t<val>a76d929d37613210f60bd233f2806b32</val>",
+                &ekey,
+            )
+            .replace(
+                "<bits>128</bits>\n\t\t\t\t<val>ce30001139e8cde103b214d7af0509e4</val>",
+                &ekey,
+            );
+        let config = parse_response(&xml).unwrap();
+        let ipsec = &config.gateway.ipsec;
+        assert_eq!(ipsec.enc_algo, EncAlgo::Aes256Cbc);
+        assert_eq!(ipsec.hmac_algo, Some(HmacAlgo::Sha384));
+        assert_eq!(ipsec.akey_s2c.len(), 48);
+
+        // <bits> 与算法要求的密钥长度不一致
+        let xml = xml.replacen("<bits>384</bits>", "<bits>256</bits>", 1);
+        match parse_response(&xml) {
+            Err(ConfigError::Invalid { field, .. }) => assert_eq!(field, "ipsec/akey-s2c"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error_status() {
+        let xml =
+            r#"<response status="error"><error>Invalid authentication cookie</error></response>"#;
+        match parse_response(xml) {
+            Err(ConfigError::Status(msg)) => assert_eq!(msg, "Invalid authentication cookie"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
-2024-10-18 17:20:29,411 - DEBUG - preferred_ip: None
-2024-10-18 17:20:29,411 - DEBUG - portal_userauthcookie: AgFAGUDXuLHFHEIpWns6FoIg1elk7VX7r3eAiTakCEmksbf0AEQECkdmuskDhSc/GLV2bA06dd1ZXQYyGjJG2XL/iMlJCETTZTi6W72yCLCqXbcW46szeXbnGHUKLySuwxXEV5Al4/fLXskL5ebBxaaGksvDgMz+7xLMc0ytMPcQ32tDhxDfFTxxDI+hWCMAethHcZ/Wav36sx3na3J43lfGfd6ZgV2u4kz33rFqIzKx/qcaSLOaGInMPx9iUK8iR/NCw+GIvunKD9f4P3Nwgd8wd76LjClq7CRdNiFngwpCGAoOuBJ6cVAU+fds3Xaju3lqQhRO4bplzYdpCF4LFA==
-2024-10-18 17:20:29,412 - DEBUG - portal_prelogonuserauthcookie: YrAUhbp/iKji8ucbQbqppmhDcZtakeqf6ZTNYTId3WL9wlXpT/jffgiPu1zq4N5eKmkLz+WO5Nsyj4scaOiaXQIHK1t9MzpiAVJeqoRQVXs03VtwQNecm3Zb4sjO/XKw64OQRvJ2fCXq/gmEBoiU5nrvzTy/IzS3VtIVZPKUmI+9w8Tx7WmOD9+a+AiQvsAFlXamFGOkb7QAAIT+4KXLEbQRURyEI/+FXP7bWZ0liEXCX9zKHQ/zxPuNzVYFhVulqOrdbL/AlGSzPAP1LS01aYyt+FGPyPCt7xY4R1l9TOpjpSQrugKr41JAMNfytrhcCH+MvC3Ym+LLmrpAQXMXnQ==
-2024-10-18 17:20:29,413 - DEBUG - ipv6: None
\ No newline at end of file
@@ -0,0 +1,284 @@
+use super::prelogin::SamlMethod;
+use super::saml::{percent_decode, SamlBrowser, SamlObservation, SamlWatcher};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const INDEX_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>GlobalProtect SAML</title></head>
+<body>
+<h3>GlobalProtect SAML login</h3>
+<p>1. <a href="/saml" target="_blank">Open the SAML login page</a> and sign in.</p>
+<p>2. When the browser tries to open a <code>globalprotectcallback:</code> link, or shows a
+blank page, paste that link or the page source below.</p>
+<form method="POST" action="/callback">
+<textarea name="data" rows="12" cols="100"></textarea><br>
+<input type="submit" value="Submit">
+</form>
+</body>
+</html>
+"#;
+
+/// 回调请求体的上限, 足够容纳粘贴的 SAML 结果页面
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// 没有图形界面时使用的 SAML 浏览器
+///
+/// 在本地回环地址上启动一个 HTTP 服务, 用户在任意浏览器中打开其中的 SAML 页面完成登录,
+/// 然后把 globalprotectcallback: 链接或结果页面提交到 `/callback`.
+/// 也可以把 globalprotectcallback: 协议处理程序配置为跳转到
+/// `http://127.0.0.1:<port>/callback?data=<url>`.
+pub struct HeadlessBrowser {
+    listener: TcpListener,
+    /// 把 SAML 请求另存为本地 HTML 文件
+    pub html_path: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+}
+
+impl HeadlessBrowser {
+    pub fn bind(addr: &str) -> std::io::Result<Self> {
+        Ok(HeadlessBrowser {
+            listener: TcpListener::bind(addr)?,
+            html_path: None,
+            timeout: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl SamlBrowser for HeadlessBrowser {
+    fn authenticate(
+        &mut self,
+        method: SamlMethod,
+        request: &str,
+        watcher: &mut SamlWatcher,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let addr = self.local_addr()?;
+        if let Some(path) = &self.html_path {
+            std::fs::write(path, saml_page(method, request))?;
+            log::info!("SAML login page written to {}", path.display());
+        }
+        if method == SamlMethod::Redirect {
+            log::info!("SAML login URL: {}", request);
+        }
+        log::info!("Open http://{}/ in a browser to complete SAML login", addr);
+
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        self.listener.set_nonblocking(deadline.is_some())?;
+        while !watcher.is_done() {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if deadline.is_some_and(|d| Instant::now() >= d) {
+                        return Err("timed out waiting for SAML callback".into());
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Err(e) = handle_connection(stream, method, request, watcher) {
+                log::warn!("SAML callback connection failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    method: SamlMethod,
+    request: &str,
+    watcher: &mut SamlWatcher,
+) -> Result<(), Box<dyn std::error::Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let verb = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or("/").to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return respond(
+            &mut stream,
+            "413 Payload Too Large",
+            "text/plain",
+            "Request body too large\n",
+        );
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    match (verb.as_str(), path) {
+        ("GET", "/") => respond(&mut stream, "200 OK", "text/html", INDEX_PAGE),
+        ("GET", "/saml") => match method {
+            SamlMethod::Redirect => {
+                let header = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    request
+                );
+                stream.write_all(header.as_bytes())?;
+                Ok(())
+            }
+            SamlMethod::Post => respond(&mut stream, "200 OK", "text/html", request),
+        },
+        (_, "/callback") => {
+            let form = match verb.as_str() {
+                "POST" => String::from_utf8_lossy(&body).into_owned(),
+                _ => query.to_string(),
+            };
+            for data in form_values(&form, "data") {
+                let data = data.trim().to_string();
+                if data.starts_with("globalprotectcallback:") {
+                    watcher.observe(SamlObservation::Url(data));
+                } else {
+                    watcher.observe(SamlObservation::Html(data));
+                }
+            }
+            match watcher.is_done() {
+                true => respond(
+                    &mut stream,
+                    "200 OK",
+                    "text/plain",
+                    "SAML login received, you can close this window.\n",
+                ),
+                false => respond(
+                    &mut stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    "No SAML credentials found in the submitted data.\n",
+                ),
+            }
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found\n"),
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn form_values(form: &str, name: &str) -> Vec<String> {
+    form.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+        .collect()
+}
+
+/// REDIRECT 方式的请求是一个 URL, 包装成跳转页面保存
+fn saml_page(method: SamlMethod, request: &str) -> String {
+    match method {
+        SamlMethod::Post => request.to_string(),
+        SamlMethod::Redirect => format!(
+            r#"<!DOCTYPE html><html><head><meta http-equiv="refresh" content="0; url={0}"></head><body><a href="{0}">Continue to SAML login</a></body></html>"#,
+            request.replace('"', "&quot;")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_headless_callback() {
+        let mut browser = HeadlessBrowser::bind("127.0.0.1:0").unwrap();
+        browser.timeout = Some(Duration::from_secs(10));
+        let addr = browser.local_addr().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let mut watcher = SamlWatcher::new();
+            browser
+                .authenticate(
+                    SamlMethod::Redirect,
+                    "https://idp.example.com/sso",
+                    &mut watcher,
+                )
+                .map(|_| watcher.into_result().unwrap())
+                .map_err(|e| e.to_string())
+        });
+
+        let response = send(addr, "GET /saml HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 302"));
+        assert!(response.contains("Location: https://idp.example.com/sso"));
+
+        let body = "data=globalprotectcallback%3Acas-as%3D1%26un%3Dalice%26token%3DT0KEN";
+        let response = send(
+            addr,
+            &format!(
+                "POST /callback HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        let result = handle.join().unwrap().unwrap();
+        assert_eq!(result.username, "alice");
+        assert_eq!(result.prelogin_cookie.as_deref(), Some("T0KEN"));
+    }
+
+    #[test]
+    fn test_rejects_large_body() {
+        let mut browser = HeadlessBrowser::bind("127.0.0.1:0").unwrap();
+        browser.timeout = Some(Duration::from_secs(5));
+        let addr = browser.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut watcher = SamlWatcher::new();
+            let _ = browser.authenticate(SamlMethod::Post, "<html></html>", &mut watcher);
+        });
+
+        let response = send(
+            addr,
+            &format!(
+                "POST /callback HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY_LEN + 1
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 413"));
+    }
+}
@@ -0,0 +1,25 @@
+use crate::libs::cert::ClientCertificate;
+use crate::libs::tls::{client_config, TlsVerify};
+use reqwest::cookie::Jar;
+use reqwest::Client;
+use std::sync::Arc;
+
+/// 访问门户和网关时共用的 HTTP 设置
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// 门户或网关要求机器证书时使用的客户端证书
+    pub client_cert: Option<ClientCertificate>,
+    /// 服务器证书的验证方式, 默认使用系统根证书
+    pub verify: TlsVerify,
+    /// 客户端的 cookie 存储, 网关 getconfig 需要在其中加入 CLIENTOS
+    pub cookies: Arc<Jar>,
+}
+
+/// 创建 prelogin, login 和 getconfig 共用的 HTTP 客户端
+pub fn build_client(options: &HttpOptions) -> Result<Client, Box<dyn std::error::Error>> {
+    let tls = client_config(&options.verify, options.client_cert.as_ref())?;
+    Ok(Client::builder()
+        .cookie_provider(options.cookies.clone())
+        .use_preconfigured_tls((*tls).clone())
+        .build()?)
+}
@@ -0,0 +1,299 @@
+use base64::prelude::*;
+use ipnet::IpNet;
+use pnet::datalink::{self, NetworkInterface};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// 客户端上报的操作系统, 网关据此选择下发的配置和 HIP 策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientOs {
+    Windows,
+    MacOs,
+    Linux,
+}
+
+impl ClientOs {
+    /// clientos 字段的取值
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientOs::Windows => "Windows",
+            ClientOs::MacOs => "Mac",
+            ClientOs::Linux => "Linux",
+        }
+    }
+}
+
+impl FromStr for ClientOs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "windows" | "win" => Ok(ClientOs::Windows),
+            "mac" | "macos" | "darwin" => Ok(ClientOs::MacOs),
+            "linux" => Ok(ClientOs::Linux),
+            _ => Err(format!("unknown client os: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ClientOs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 提交给门户和网关的客户端信息
+///
+/// prelogin, 门户 getconfig, login 和网关 getconfig 都从同一个 `ClientIdentity` 取值,
+/// 避免同一次连接中上报互相矛盾的主机信息. 字段都是公开的, 可以在预设的基础上单独覆盖.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub os: ClientOs,
+    /// os-version, 例如 "Microsoft Windows 11 Pro , 64-bit"
+    pub os_version: String,
+    /// GlobalProtect 客户端版本, 即 app-version / clientgpversion
+    pub app_version: String,
+    /// 协议版本 clientVer
+    pub client_version: String,
+    /// login.esp 的 computer 字段, 通常是主机名
+    pub computer: String,
+    pub serial_no: Option<String>,
+    pub joined_domain: Option<String>,
+    /// 形如 `f2-e3-f7-1c-85-b3`
+    pub mac_addr: Option<String>,
+    /// 本机接口地址, 依次作为 addr1, addr2, ... 上报
+    pub addresses: Vec<IpNet>,
+    pub client_ip: Option<Ipv4Addr>,
+    pub client_ipv6: Option<Ipv6Addr>,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        ClientIdentity::windows()
+    }
+}
+
+impl ClientIdentity {
+    fn preset(os: ClientOs, os_version: &str) -> Self {
+        ClientIdentity {
+            os,
+            os_version: os_version.to_string(),
+            app_version: "6.0.8-601".to_string(),
+            client_version: "4100".to_string(),
+            computer: String::new(),
+            serial_no: None,
+            joined_domain: None,
+            mac_addr: None,
+            addresses: Vec::new(),
+            client_ip: None,
+            client_ipv6: None,
+        }
+    }
+
+    pub fn windows() -> Self {
+        Self::preset(ClientOs::Windows, "Microsoft Windows 11 Pro , 64-bit")
+    }
+
+    pub fn macos() -> Self {
+        Self::preset(ClientOs::MacOs, "Apple Mac OS X 14.6.1")
+    }
+
+    pub fn linux() -> Self {
+        Self::preset(ClientOs::Linux, "Linux Ubuntu 24.04")
+    }
+
+    pub fn for_os(os: ClientOs) -> Self {
+        match os {
+            ClientOs::Windows => Self::windows(),
+            ClientOs::MacOs => Self::macos(),
+            ClientOs::Linux => Self::linux(),
+        }
+    }
+
+    /// 从本机网络接口和主机名补全尚未设置的字段, 已经覆盖的字段保持不变
+    pub fn detect(mut self) -> Self {
+        if self.computer.is_empty() {
+            self.computer = hostname().unwrap_or_else(|| "localhost".to_string());
+        }
+        self.fill_from_interfaces(&datalink::interfaces());
+        self
+    }
+
+    fn fill_from_interfaces(&mut self, interfaces: &[NetworkInterface]) {
+        let usable: Vec<&NetworkInterface> = interfaces
+            .iter()
+            .filter(|iface| iface.is_up() && !iface.is_loopback() && !iface.ips.is_empty())
+            .collect();
+
+        if self.mac_addr.is_none() {
+            self.mac_addr = usable
+                .iter()
+                .filter_map(|iface| iface.mac)
+                .find(|mac| !mac.is_zero())
+                .map(|mac| mac.to_string().replace(':', "-"));
+        }
+
+        let addresses: Vec<IpNet> = usable
+            .iter()
+            .flat_map(|iface| iface.ips.iter())
+            .filter(|ip| match ip.ip() {
+                IpAddr::V4(_) => true,
+                // 链路本地地址对网关没有意义
+                IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) != 0xfe80,
+            })
+            .filter_map(|ip| IpNet::new(ip.ip(), ip.prefix()).ok())
+            .collect();
+
+        if self.addresses.is_empty() {
+            self.addresses = addresses.clone();
+        }
+        if self.client_ip.is_none() {
+            self.client_ip = addresses.iter().find_map(|net| match net.addr() {
+                IpAddr::V4(v4) => Some(v4),
+                IpAddr::V6(_) => None,
+            });
+        }
+        if self.client_ipv6.is_none() {
+            self.client_ipv6 = addresses.iter().find_map(|net| match net.addr() {
+                IpAddr::V6(v6) => Some(v6),
+                IpAddr::V4(_) => None,
+            });
+        }
+    }
+
+    /// 网关 getconfig 请求携带的 CLIENTOS cookie
+    pub fn clientos_cookie(&self) -> String {
+        format!("CLIENTOS={}", BASE64_STANDARD.encode(self.os.as_str()))
+    }
+
+    /// prelogin, login 和门户 getconfig 共有的字段
+    pub fn form_fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("clientVer", self.client_version.clone()),
+            ("clientos", self.os.as_str().to_string()),
+            ("os-version", self.os_version.clone()),
+        ]
+    }
+
+    /// 网关 getconfig 需要的主机信息
+    pub fn host_fields(&self) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        for (i, addr) in self.addresses.iter().enumerate() {
+            fields.push((format!("addr{}", i + 1), addr.to_string()));
+        }
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+        fields.extend([
+            ("os-version".to_string(), self.os_version.clone()),
+            ("app-version".to_string(), self.app_version.clone()),
+            ("clientos".to_string(), self.os.as_str().to_string()),
+            (
+                "client-ip".to_string(),
+                self.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            ),
+            (
+                "client-ipv6".to_string(),
+                self.client_ipv6
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default(),
+            ),
+            ("serialno".to_string(), optional(&self.serial_no)),
+            ("mac-addr".to_string(), optional(&self.mac_addr)),
+            ("joined-domain".to_string(), optional(&self.joined_domain)),
+        ]);
+        fields
+    }
+}
+
+fn hostname() -> Option<String> {
+    for source in ["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(name) = std::fs::read_to_string(source) {
+            let name = name.trim();
+            if !name.is_empty() {
+                return Some(name.to_string());
+            }
+        }
+    }
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::ipnetwork::IpNetwork;
+    use pnet::util::MacAddr;
+
+    fn interface(name: &str, flags: u32, mac: Option<MacAddr>, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 0,
+            mac,
+            ips: ips
+                .iter()
+                .map(|ip| ip.parse().unwrap())
+                .collect::<Vec<IpNetwork>>(),
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_fill_from_interfaces() {
+        // IFF_UP, IFF_UP | IFF_LOOPBACK
+        let up = 0x1;
+        let loopback = 0x9;
+        let interfaces = vec![
+            interface("lo", loopback, Some(MacAddr::zero()), &["127.0.0.1/8"]),
+            interface(
+                "docker0",
+                0x0,
+                Some(MacAddr::broadcast()),
+                &["172.17.0.1/16"],
+            ),
+            interface(
+                "eth0",
+                up,
+                Some(MacAddr::new(0xf2, 0xe3, 0xf7, 0x1c, 0x85, 0xb3)),
+                &["172.16.200.227/22", "fe80::1/64", "2001:db8::5/64"],
+            ),
+        ];
+
+        let mut identity = ClientIdentity::linux();
+        identity.serial_no = Some("DZVQPM3".to_string());
+        identity.fill_from_interfaces(&interfaces);
+
+        assert_eq!(identity.mac_addr.as_deref(), Some("f2-e3-f7-1c-85-b3"));
+        assert_eq!(identity.client_ip, Some(Ipv4Addr::new(172, 16, 200, 227)));
+        assert_eq!(identity.client_ipv6, Some("2001:db8::5".parse().unwrap()));
+        assert_eq!(identity.addresses.len(), 2);
+
+        let fields = identity.host_fields();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(field("addr1"), Some("172.16.200.227/22"));
+        assert_eq!(field("addr2"), Some("2001:db8::5/64"));
+        assert_eq!(field("clientos"), Some("Linux"));
+        assert_eq!(field("serialno"), Some("DZVQPM3"));
+        assert_eq!(field("joined-domain"), Some(""));
+    }
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(
+            ClientIdentity::default().clientos_cookie(),
+            "CLIENTOS=V2luZG93cw=="
+        );
+        assert_eq!(ClientIdentity::macos().os.as_str(), "Mac");
+        assert_eq!("linux".parse::<ClientOs>().unwrap(), ClientOs::Linux);
+        assert_eq!(
+            ClientIdentity::for_os(ClientOs::Linux),
+            ClientIdentity::linux()
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use super::credential::Credentials;
+use super::identity::ClientIdentity;
+use reqwest::Client;
+use std::fmt;
+
+/// login.esp 成功后返回的认证信息, getconfig 使用它换取隧道配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthCookie {
+    pub authcookie: String,
+    pub portal: String,
+    pub user: String,
+    pub domain: Option<String>,
+    pub preferred_ip: Option<String>,
+    pub preferred_ipv6: Option<String>,
+    pub connection_type: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum LoginError {
+    /// 用户名或密码错误
+    InvalidCredentials(String),
+    /// 网关拒绝了登录请求
+    Rejected(String),
+    /// 无法识别的响应
+    Malformed(String),
+    /// 用户没有回答 challenge
+    Aborted(String),
+    Http(reqwest::Error),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::InvalidCredentials(msg) => write!(f, "login failed: {}", msg),
+            LoginError::Rejected(msg) => write!(f, "gateway rejected login: {}", msg),
+            LoginError::Malformed(msg) => write!(f, "unexpected login response: {}", msg),
+            LoginError::Aborted(msg) => write!(f, "login challenge aborted: {}", msg),
+            LoginError::Http(e) => write!(f, "login request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoginError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for LoginError {
+    fn from(e: reqwest::Error) -> Self {
+        LoginError::Http(e)
+    }
+}
+
+/// 登录过程中的 OTP / RADIUS challenge 回调
+pub trait ChallengeHandler {
+    /// 向用户展示 `prompt` 并返回用户的回答
+    fn respond(&mut self, prompt: &str) -> Result<String, Box<dyn std::error::Error>>;
+}
+
+impl<F> ChallengeHandler for F
+where
+    F: FnMut(&str) -> Result<String, Box<dyn std::error::Error>>,
+{
+    fn respond(&mut self, prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self(prompt)
+    }
+}
+
+/// login.esp 的一次响应
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginResponse {
+    Success(AuthCookie),
+    /// 网关要求再提交一次 OTP, 回答时需要带上 `input_str`
+    Challenge {
+        message: String,
+        input_str: String,
+    },
+}
+
+/// 防止网关无限下发 challenge
+const MAX_CHALLENGE_ROUNDS: usize = 8;
+
+pub async fn login(
+    client: &Client,
+    gateway: &str,
+    credentials: &Credentials,
+    identity: &ClientIdentity,
+    challenges: &mut dyn ChallengeHandler,
+) -> Result<AuthCookie, LoginError> {
+    let url = format!("https://{}/ssl-vpn/login.esp", gateway);
+
+    let mut data = credentials.form_fields();
+    data.extend(identity.form_fields());
+    data.push(("prot", "https:".to_string()));
+    data.push(("server", gateway.to_string()));
+    data.push(("jnlpReady", "jnlpReady".to_string()));
+    data.push(("computer", identity.computer.clone()));
+    data.push(("ok", "Login".to_string()));
+    data.push(("direct", "yes".to_string()));
+    data.push(("ipv6-support", "yes".to_string()));
+    submit(client, &url, &data, challenges).await
+}
+
+/// 提交登录表单, 回答网关下发的 challenge 直到登录成功
+async fn submit(
+    client: &Client,
+    url: &str,
+    data: &[(&'static str, String)],
+    challenges: &mut dyn ChallengeHandler,
+) -> Result<AuthCookie, LoginError> {
+    let mut input_str = String::new();
+    let mut answer = None;
+    for round in 0..=MAX_CHALLENGE_ROUNDS {
+        let mut form = data.to_vec();
+        form.push(("inputStr", input_str.clone()));
+        // challenge 的回答通过 passwd 字段提交
+        if let Some(answer) = answer.take() {
+            form.retain(|(name, _)| *name != "passwd");
+            form.push(("passwd", answer));
+        }
+
+        let response = client.post(url).form(&form).send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(parse_error(&text).unwrap_or_else(|| {
+                LoginError::Rejected(format!("HTTP {}: {}", status, text.trim()))
+            }));
+        }
+
+        match parse_login_response(&text)? {
+            LoginResponse::Success(cookie) => return Ok(cookie),
+            LoginResponse::Challenge {
+                message,
+                input_str: next,
+            } => {
+                if round == MAX_CHALLENGE_ROUNDS {
+                    break;
+                }
+                log::info!("login challenge: {}", message);
+                let reply = challenges
+                    .respond(&message)
+                    .map_err(|e| LoginError::Aborted(e.to_string()))?;
+                answer = Some(reply);
+                input_str = next;
+            }
+        }
+    }
+    Err(LoginError::Rejected(format!(
+        "gave up after {} challenge rounds",
+        MAX_CHALLENGE_ROUNDS
+    )))
+}
+
+/// 解析 login.esp 的响应
+///
+/// 成功时网关返回一组按位置排列的参数, 旧版本以 JavaScript 数组的形式返回,
+/// 新版本包裹在 `<argument>` 元素中, 两种格式的参数顺序相同.
+pub fn parse_login_response(text: &str) -> Result<LoginResponse, LoginError> {
+    if let Some(status) = js_value(text, "var respStatus") {
+        if status.eq_ignore_ascii_case("challenge") {
+            let message = js_value(text, "var respMsg").unwrap_or_default();
+            let input_str = js_value(text, "thisForm.inputStr.value")
+                .ok_or_else(|| LoginError::Malformed("challenge without inputStr".into()))?;
+            return Ok(LoginResponse::Challenge { message, input_str });
+        }
+    }
+    if let Some(err) = parse_error(text) {
+        return Err(err);
+    }
+
+    let args = match xml_arguments(text).or_else(|| js_arguments(text)) {
+        Some(args) => args,
+        None => return Err(LoginError::Malformed(text.trim().to_string())),
+    };
+    let arg = |i: usize| -> Option<String> {
+        args.get(i)
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty() && *v != "(null)")
+            .map(str::to_string)
+    };
+
+    let authcookie = arg(1).ok_or_else(|| LoginError::Malformed("missing authcookie".into()))?;
+    Ok(LoginResponse::Success(AuthCookie {
+        authcookie,
+        portal: arg(3).ok_or_else(|| LoginError::Malformed("missing portal".into()))?,
+        user: arg(4).ok_or_else(|| LoginError::Malformed("missing user".into()))?,
+        domain: arg(7),
+        connection_type: arg(12),
+        preferred_ip: arg(15),
+        preferred_ipv6: arg(18),
+    }))
+}
+
+/// 识别 `var respStatus = "Error"; var respMsg = "...";` 形式的错误响应
+fn parse_error(text: &str) -> Option<LoginError> {
+    let status = js_value(text, "var respStatus")?;
+    if status.eq_ignore_ascii_case("success") {
+        return None;
+    }
+    let msg = js_value(text, "var respMsg").unwrap_or(status);
+    let lower = msg.to_ascii_lowercase();
+    if lower.contains("invalid username or password") || lower.contains("authentication failed") {
+        Some(LoginError::InvalidCredentials(msg))
+    } else {
+        Some(LoginError::Rejected(msg))
+    }
+}
+
+/// 读取 `<lhs> = "value";` 形式的 JavaScript 赋值
+fn js_value(text: &str, lhs: &str) -> Option<String> {
+    let start = text.find(lhs)?;
+    let rest = &text[start..];
+    let rest = &rest[rest.find('=')? + 1..];
+    let quote_start = rest.find('"')? + 1;
+    let quote_end = quote_start + rest[quote_start..].find('"')?;
+    Some(rest[quote_start..quote_end].to_string())
+}
+
+fn xml_arguments(text: &str) -> Option<Vec<String>> {
+    if !text.contains("<argument") {
+        return None;
+    }
+    let mut args = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("<argument") {
+        rest = &rest[start + "<argument".len()..];
+        let close = rest.find('>')?;
+        if rest[..close].ends_with('/') {
+            // 自闭合的 <argument/>
+            args.push(String::new());
+            rest = &rest[close + 1..];
+            continue;
+        }
+        rest = &rest[close + 1..];
+        let end = rest.find("</argument>")?;
+        args.push(unescape(&rest[..end]));
+        rest = &rest[end..];
+    }
+    Some(args)
+}
+
+fn js_arguments(text: &str) -> Option<Vec<String>> {
+    let start = text.find('[')?;
+    let end = start + text[start..].find(']')?;
+    let mut args = Vec::new();
+    let mut rest = &text[start + 1..end];
+    while let Some(open) = rest.find('"') {
+        let close = open + 1 + rest[open + 1..].find('"')?;
+        args.push(rest[open + 1..close].to_string());
+        rest = &rest[close + 1..];
+    }
+    match args.is_empty() {
+        true => None,
+        false => Some(args),
+    }
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn success(text: &str) -> AuthCookie {
+        match parse_login_response(text) {
+            Ok(LoginResponse::Success(cookie)) => cookie,
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_jnlp_login() {
+        let text = r#"<?xml version="1.0" encoding="utf-8"?> <jnlp> <application-desc>
+            <argument>(null)</argument>
+            <argument>cbd2a6ea91d8c27b6d8bd16e7cc3bd11</argument>
+            <argument>ffbde2e5a3a7f3c8a1b0ea0e5e3a3eb1f8b9f3a0</argument>
+            <argument>GP-GW-SHAP-N</argument>
+            <argument>ling.pcheng@fujitsu.com</argument>
+            <argument>SAML-Auth</argument>
+            <argument>vsys1</argument>
+            <argument>fujitsu</argument>
+            <argument>(null)</argument>
+            <argument/>
+            <argument></argument>
+            <argument></argument>
+            <argument>tunnel</argument>
+            <argument>-1</argument>
+            <argument>4100</argument>
+            <argument>10.193.129.116</argument>
+            <argument></argument>
+            <argument></argument>
+            <argument></argument>
+            <argument>4</argument>
+            <argument>unknown</argument>
+            </application-desc></jnlp>"#;
+
+        let cookie = success(text);
+        assert_eq!(cookie.authcookie, "cbd2a6ea91d8c27b6d8bd16e7cc3bd11");
+        assert_eq!(cookie.portal, "GP-GW-SHAP-N");
+        assert_eq!(cookie.user, "ling.pcheng@fujitsu.com");
+        assert_eq!(cookie.domain.as_deref(), Some("fujitsu"));
+        assert_eq!(cookie.connection_type.as_deref(), Some("tunnel"));
+        assert_eq!(cookie.preferred_ip.as_deref(), Some("10.193.129.116"));
+        assert_eq!(cookie.preferred_ipv6, None);
+    }
+
+    #[test]
+    fn test_parse_js_array_login() {
+        let text = r#"var respStatus = "Success";
+            ["(null)","a1b2c3","","GW-1","alice","LDAP","vsys1","corp","","","","","tunnel","-1","4100","10.0.0.7"]"#;
+        let cookie = success(text);
+        assert_eq!(cookie.authcookie, "a1b2c3");
+        assert_eq!(cookie.portal, "GW-1");
+        assert_eq!(cookie.preferred_ip.as_deref(), Some("10.0.0.7"));
+    }
+
+    #[test]
+    fn test_parse_login_errors() {
+        let text = r#"var respStatus = "Error";
+            var respMsg = "Invalid username or password";
+            thisForm.inputStr.value = "";"#;
+        match parse_login_response(text) {
+            Err(LoginError::InvalidCredentials(msg)) => {
+                assert_eq!(msg, "Invalid username or password")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let text = r#"var respStatus = "Error"; var respMsg = "Max login attempts exceeded";"#;
+        assert!(matches!(
+            parse_login_response(text),
+            Err(LoginError::Rejected(_))
+        ));
+        assert!(matches!(
+            parse_login_response("<html></html>"),
+            Err(LoginError::Malformed(_))
+        ));
+    }
+
+    fn challenge(round: usize) -> String {
+        format!(
+            r#"var respStatus = "Challenge";
+            var respMsg = "Enter code {0}";
+            thisForm.inputStr.value = "input-{0}";"#,
+            round
+        )
+    }
+
+    /// 依次返回 `responses`, 返回收到的每个请求体
+    async fn serve_login(listener: tokio::net::TcpListener, responses: Vec<String>) -> Vec<String> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let mut bodies = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+            bodies.push(String::from_utf8(body).unwrap());
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+        bodies
+    }
+
+    async fn run_login(
+        responses: Vec<String>,
+    ) -> (Result<AuthCookie, LoginError>, Vec<String>, Vec<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/ssl-vpn/login.esp",
+            listener.local_addr().unwrap()
+        );
+        let server = tokio::spawn(serve_login(listener, responses));
+
+        let data = vec![
+            ("user", "alice".to_string()),
+            ("passwd", "hunter2".to_string()),
+        ];
+        let mut prompts = Vec::new();
+        let mut handler = |prompt: &str| -> Result<String, Box<dyn std::error::Error>> {
+            prompts.push(prompt.to_string());
+            Ok(format!("otp{}", prompts.len()))
+        };
+        let result = submit(&Client::new(), &url, &data, &mut handler).await;
+        (result, prompts, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_login_answers_challenges() {
+        let success = r#"var respStatus = "Success";
+            ["(null)","a1b2c3","","GW-1","alice","LDAP","vsys1","corp"]"#;
+        let (result, prompts, bodies) =
+            run_login(vec![challenge(1), challenge(2), success.to_string()]).await;
+
+        assert_eq!(result.unwrap().authcookie, "a1b2c3");
+        assert_eq!(prompts, vec!["Enter code 1", "Enter code 2"]);
+        assert_eq!(bodies.len(), 3);
+        assert!(bodies[0].contains("passwd=hunter2"), "{}", bodies[0]);
+        assert!(bodies[0].ends_with("inputStr="), "{}", bodies[0]);
+        // 回答替换原来的密码, 并带上上一轮的 inputStr
+        for (round, body) in bodies[1..].iter().enumerate() {
+            let round = round + 1;
+            assert!(body.contains(&format!("passwd=otp{}", round)), "{}", body);
+            assert!(
+                body.contains(&format!("inputStr=input-{}", round)),
+                "{}",
+                body
+            );
+            assert!(!body.contains("hunter2"), "{}", body);
+            assert_eq!(body.matches("passwd=").count(), 1, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_gives_up_after_max_rounds() {
+        let responses = (0..=MAX_CHALLENGE_ROUNDS).map(challenge).collect();
+        let (result, prompts, bodies) = run_login(responses).await;
+
+        assert!(
+            matches!(result, Err(LoginError::Rejected(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(bodies.len(), MAX_CHALLENGE_ROUNDS + 1);
+        // 最后一个 challenge 不再提示用户
+        assert_eq!(prompts.len(), MAX_CHALLENGE_ROUNDS);
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let text = r#"var respStatus = "Challenge";
+            var respMsg = "Enter the 6-digit code from your token";
+            thisForm.inputStr.value = "5ef3a9d0c1b2";"#;
+        assert_eq!(
+            parse_login_response(text).unwrap(),
+            LoginResponse::Challenge {
+                message: "Enter the 6-digit code from your token".to_string(),
+                input_str: "5ef3a9d0c1b2".to_string(),
+            }
+        );
+
+        let mut prompts = Vec::new();
+        let mut handler = |prompt: &str| -> Result<String, Box<dyn std::error::Error>> {
+            prompts.push(prompt.to_string());
+            Ok("123456".to_string())
+        };
+        assert_eq!(handler.respond("code?").unwrap(), "123456");
+        assert_eq!(prompts, vec!["code?"]);
+    }
+}
@@ -1 +1,11 @@
-pub mod getconfig;
\ No newline at end of file
+pub mod credential;
+pub mod getconfig;
+pub mod headless;
+pub mod http;
+pub mod identity;
+pub mod login;
+pub mod portal;
+pub mod prelogin;
+pub mod saml;
+#[cfg(feature = "webview")]
+pub mod webview;
@@ -0,0 +1,361 @@
+use super::credential::{Credentials, Secret};
+use super::identity::ClientIdentity;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_xml_rs::from_str;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Policy {
+    #[serde(rename = "portal-name")]
+    portal_name: Option<String>,
+    version: Option<String>,
+    #[serde(rename = "portal-userauthcookie")]
+    portal_userauthcookie: Option<String>,
+    #[serde(rename = "portal-prelogonuserauthcookie")]
+    portal_prelogonuserauthcookie: Option<String>,
+    #[serde(rename = "root-ca")]
+    root_ca: Option<RootCa>,
+    #[serde(rename = "hip-collection")]
+    hip_collection: Option<RawHipCollection>,
+    gateways: Option<Gateways>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RootCa {
+    entry: Vec<CaEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CaEntry {
+    name: Option<String>,
+    cert: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawHipCollection {
+    #[serde(rename = "collect-hip-data")]
+    collect_hip_data: Option<String>,
+    #[serde(rename = "hip-report-interval")]
+    hip_report_interval: Option<String>,
+    #[serde(rename = "max-wait-time")]
+    max_wait_time: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Gateways {
+    external: Option<GatewayList>,
+    internal: Option<GatewayList>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GatewayList {
+    list: Option<GatewayEntries>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GatewayEntries {
+    entry: Vec<GatewayEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct GatewayEntry {
+    name: String,
+    description: Option<String>,
+    priority: Option<String>,
+    #[serde(rename = "priority-rule")]
+    priority_rule: Option<PriorityRule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PriorityRule {
+    entry: Vec<PriorityRuleEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PriorityRuleEntry {
+    name: String,
+    priority: Option<String>,
+}
+
+/// 门户下发的网关
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    pub address: String,
+    pub description: Option<String>,
+    /// 数值越小优先级越高
+    pub priority: u32,
+    pub internal: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HipCollection {
+    pub collect_hip_data: bool,
+    pub hip_report_interval: Option<u64>,
+    pub max_wait_time: Option<u64>,
+}
+
+/// 门户 getconfig.esp 的响应
+#[derive(Debug, Clone)]
+pub struct PortalConfig {
+    pub portal_name: Option<String>,
+    pub version: Option<String>,
+    pub gateways: Vec<Gateway>,
+    pub user_auth_cookie: Option<String>,
+    pub prelogon_user_auth_cookie: Option<String>,
+    pub hip_collection: Option<HipCollection>,
+    /// PEM 格式的根证书
+    pub root_ca: Vec<String>,
+}
+
+impl PortalConfig {
+    /// 按优先级选择外部网关, `preferred` 可以是网关地址或描述
+    pub fn choose_gateway(&self, preferred: Option<&str>) -> Option<&Gateway> {
+        if let Some(name) = preferred {
+            return self
+                .gateways
+                .iter()
+                .find(|gw| gw.address == name || gw.description.as_deref() == Some(name));
+        }
+
+        let external = self.gateways.iter().filter(|gw| !gw.internal);
+        match external.min_by_key(|gw| gw.priority) {
+            Some(gw) => Some(gw),
+            None => self.gateways.iter().min_by_key(|gw| gw.priority),
+        }
+    }
+
+    /// 门户下发的 cookie 可以代替密码登录网关
+    pub fn gateway_credentials(&self, username: &str) -> Option<Credentials> {
+        if let Some(cookie) = &self.user_auth_cookie {
+            return Some(Credentials::new(
+                username,
+                Secret::PortalUserAuthCookie(cookie.clone()),
+            ));
+        }
+        self.prelogon_user_auth_cookie.as_ref().map(|cookie| {
+            Credentials::new(
+                username,
+                Secret::PortalPrelogonUserAuthCookie(cookie.clone()),
+            )
+        })
+    }
+}
+
+pub async fn get_portal_config(
+    client: &Client,
+    portal: &str,
+    credentials: &Credentials,
+    identity: &ClientIdentity,
+) -> Result<PortalConfig, Box<dyn std::error::Error>> {
+    let url = format!("https://{}/global-protect/getconfig.esp", portal);
+
+    let mut data = credentials.form_fields();
+    data.extend(identity.form_fields());
+    data.push(("server", portal.to_string()));
+    data.push(("computer", identity.computer.clone()));
+    data.push(("clientgpversion", identity.app_version.clone()));
+    data.push(("ipv6-support", "yes".to_string()));
+    data.push(("inputStr", String::new()));
+    data.push(("config-digest", String::new()));
+
+    let response = client.post(&url).form(&data).send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("portal getconfig failed ({}): {}", status, text.trim()).into());
+    }
+    parse_portal_config(&text)
+}
+
+pub fn parse_portal_config(xml: &str) -> Result<PortalConfig, Box<dyn std::error::Error>> {
+    let policy: Policy = from_str(xml)?;
+
+    let mut gateways = Vec::new();
+    if let Some(list) = policy.gateways {
+        for (entries, internal) in [(list.external, false), (list.internal, true)] {
+            let entries = entries.and_then(|l| l.list).unwrap_or_default().entry;
+            for entry in entries {
+                gateways.push(Gateway {
+                    priority: gateway_priority(&entry)?,
+                    address: entry.name.trim().to_string(),
+                    description: text(entry.description),
+                    internal,
+                });
+            }
+        }
+    }
+
+    let hip_collection = match policy.hip_collection {
+        Some(hip) => Some(HipCollection {
+            collect_hip_data: text(hip.collect_hip_data).as_deref() == Some("yes"),
+            hip_report_interval: number(hip.hip_report_interval)?,
+            max_wait_time: number(hip.max_wait_time)?,
+        }),
+        None => None,
+    };
+
+    let root_ca = policy
+        .root_ca
+        .unwrap_or_default()
+        .entry
+        .into_iter()
+        .filter_map(|entry| {
+            if entry.cert.is_none() {
+                log::warn!("root-ca entry {:?} has no certificate", entry.name);
+            }
+            text(entry.cert)
+        })
+        .collect();
+
+    Ok(PortalConfig {
+        portal_name: text(policy.portal_name),
+        version: text(policy.version),
+        gateways,
+        user_auth_cookie: cookie(policy.portal_userauthcookie),
+        prelogon_user_auth_cookie: cookie(policy.portal_prelogonuserauthcookie),
+        hip_collection,
+        root_ca,
+    })
+}
+
+/// 优先使用 priority-rule 中 "Any" 规则的优先级
+fn gateway_priority(entry: &GatewayEntry) -> Result<u32, Box<dyn std::error::Error>> {
+    let rules = entry.priority_rule.as_ref().map(|r| r.entry.as_slice());
+    let rule = rules
+        .unwrap_or_default()
+        .iter()
+        .find(|rule| rule.name == "Any")
+        .and_then(|rule| rule.priority.clone());
+    let priority = number(rule.or_else(|| entry.priority.clone()))?;
+    Ok(priority.unwrap_or(u32::MAX))
+}
+
+fn text(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// 超出 `T` 范围的值视为错误, 不截断
+fn number<T>(value: Option<String>) -> Result<Option<T>, Box<dyn std::error::Error>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + 'static,
+{
+    match text(value) {
+        Some(v) => Ok(Some(v.parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// 门户用 "empty" 表示没有下发 cookie
+fn cookie(value: Option<String>) -> Option<String> {
+    text(value).filter(|v| v != "empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORTAL_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+    <policy>
+        <portal-name>GP-Portal</portal-name>
+        <portal-config-version>4100</portal-config-version>
+        <version>10.1.6</version>
+        <client-role>global-protect-full</client-role>
+        <root-ca>
+            <entry name="Corp-Root">
+                <cert>-----BEGIN CERTIFICATE-----
+MIIB
+-----END CERTIFICATE-----</cert>
+                <install-in-cert-store>yes</install-in-cert-store>
+            </entry>
+        </root-ca>
+        <connect-method>on-demand</connect-method>
+        <portal-userauthcookie>AgFAGUDXuLHFHEIp</portal-userauthcookie>
+        <portal-prelogonuserauthcookie>empty</portal-prelogonuserauthcookie>
+        <hip-collection>
+            <hip-report-interval>3600</hip-report-interval>
+            <max-wait-time>20</max-wait-time>
+            <collect-hip-data>yes</collect-hip-data>
+        </hip-collection>
+        <gateways>
+            <cutoff-time>5</cutoff-time>
+            <external>
+                <list>
+                    <entry name="gw-tokyo.example.com">
+                        <priority-rule>
+                            <entry name="Any">
+                                <priority>2</priority>
+                            </entry>
+                        </priority-rule>
+                        <manual>yes</manual>
+                        <description>Tokyo</description>
+                    </entry>
+                    <entry name="gw-shanghai.example.com">
+                        <priority>1</priority>
+                        <manual>yes</manual>
+                        <description>Shanghai</description>
+                    </entry>
+                </list>
+            </external>
+        </gateways>
+    </policy>"#;
+
+    #[test]
+    fn test_parse_portal_config() {
+        let config = parse_portal_config(PORTAL_XML).unwrap();
+
+        assert_eq!(config.portal_name.as_deref(), Some("GP-Portal"));
+        assert_eq!(config.user_auth_cookie.as_deref(), Some("AgFAGUDXuLHFHEIp"));
+        assert_eq!(config.prelogon_user_auth_cookie, None);
+        assert_eq!(config.root_ca.len(), 1);
+        assert!(config.root_ca[0].starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(
+            config.hip_collection,
+            Some(HipCollection {
+                collect_hip_data: true,
+                hip_report_interval: Some(3600),
+                max_wait_time: Some(20),
+            })
+        );
+
+        assert_eq!(config.gateways.len(), 2);
+        assert_eq!(config.gateways[0].priority, 2);
+        let gw = config.choose_gateway(None).unwrap();
+        assert_eq!(gw.address, "gw-shanghai.example.com");
+        let gw = config.choose_gateway(Some("Tokyo")).unwrap();
+        assert_eq!(gw.address, "gw-tokyo.example.com");
+
+        let credentials = config.gateway_credentials("alice").unwrap();
+        assert_eq!(
+            credentials.secret,
+            Secret::PortalUserAuthCookie("AgFAGUDXuLHFHEIp".to_string())
+        );
+    }
+
+    #[test]
+    fn test_gateway_priority_range() {
+        let entry = |priority: &str| GatewayEntry {
+            priority: Some(priority.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(gateway_priority(&entry("4294967295")).unwrap(), u32::MAX);
+        assert!(gateway_priority(&entry("4294967296")).is_err());
+        assert!(gateway_priority(&entry("-1")).is_err());
+        assert_eq!(
+            gateway_priority(&GatewayEntry::default()).unwrap(),
+            u32::MAX
+        );
+    }
+}
@@ -0,0 +1,217 @@
+use super::identity::ClientIdentity;
+use base64::prelude::*;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_xml_rs::from_str;
+
+/// prelogin 请求的目标: 门户或网关
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreloginTarget {
+    Portal,
+    Gateway,
+}
+
+impl PreloginTarget {
+    pub fn path(&self) -> &'static str {
+        match self {
+            PreloginTarget::Portal => "/global-protect/prelogin.esp",
+            PreloginTarget::Gateway => "/ssl-vpn/prelogin.esp",
+        }
+    }
+}
+
+/// SAML 请求的提交方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamlMethod {
+    /// saml-request 是 IdP 的 URL
+    Redirect,
+    /// saml-request 是一个自动提交的 HTML 表单
+    Post,
+}
+
+/// 服务器要求的认证方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    Saml { method: SamlMethod, request: String },
+    Password,
+    Certificate,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Response {
+    status: Option<String>,
+    msg: Option<String>,
+    #[serde(rename = "authentication-message")]
+    authentication_message: Option<String>,
+    #[serde(rename = "username-label")]
+    username_label: Option<String>,
+    #[serde(rename = "password-label")]
+    password_label: Option<String>,
+    #[serde(rename = "saml-auth-method")]
+    saml_auth_method: Option<String>,
+    #[serde(rename = "saml-request")]
+    saml_request: Option<String>,
+    region: Option<String>,
+    #[serde(rename = "panos-version")]
+    panos_version: Option<String>,
+}
+
+/// prelogin.esp 的响应
+#[derive(Debug, Clone)]
+pub struct Prelogin {
+    pub target: PreloginTarget,
+    pub auth_message: Option<String>,
+    pub username_label: Option<String>,
+    pub password_label: Option<String>,
+    pub saml_method: Option<SamlMethod>,
+    /// 已经 base64 解码的 SAML 请求
+    pub saml_request: Option<String>,
+    pub region: Option<String>,
+    pub panos_version: Option<String>,
+}
+
+impl Prelogin {
+    pub fn auth_method(&self) -> AuthMethod {
+        if let (Some(method), Some(request)) = (self.saml_method, &self.saml_request) {
+            return AuthMethod::Saml {
+                method,
+                request: request.clone(),
+            };
+        }
+        // 只允许证书认证的门户不会下发用户名/密码的输入提示
+        if self.username_label.is_some() || self.password_label.is_some() {
+            AuthMethod::Password
+        } else {
+            AuthMethod::Certificate
+        }
+    }
+}
+
+pub async fn prelogin(
+    client: &Client,
+    server: &str,
+    target: PreloginTarget,
+    identity: &ClientIdentity,
+) -> Result<Prelogin, Box<dyn std::error::Error>> {
+    let url = format!("https://{}{}", server, target.path());
+
+    let mut data = identity.form_fields();
+    data.push(("tmp", "tmp".to_string()));
+    data.push(("ipv6-support", "yes".to_string()));
+    data.push(("default-browser", "0".to_string()));
+    data.push(("cas-support", "yes".to_string()));
+
+    let response = client.post(&url).form(&data).send().await?;
+    let text = response.text().await?;
+    parse_prelogin(&text, target)
+}
+
+pub fn parse_prelogin(
+    xml: &str,
+    target: PreloginTarget,
+) -> Result<Prelogin, Box<dyn std::error::Error>> {
+    let response: Response = from_str(xml)?;
+
+    let status = text(response.status).unwrap_or_default();
+    if !status.eq_ignore_ascii_case("success") {
+        let msg = text(response.msg).unwrap_or(status);
+        return Err(format!("prelogin failed: {}", msg).into());
+    }
+
+    let saml_method = match text(response.saml_auth_method).as_deref() {
+        None => None,
+        Some("REDIRECT") => Some(SamlMethod::Redirect),
+        Some("POST") => Some(SamlMethod::Post),
+        Some(other) => return Err(format!("unknown saml-auth-method: {}", other).into()),
+    };
+    let saml_request = match text(response.saml_request) {
+        Some(request) => Some(String::from_utf8(BASE64_STANDARD.decode(request)?)?),
+        None => None,
+    };
+
+    Ok(Prelogin {
+        target,
+        auth_message: text(response.authentication_message),
+        username_label: text(response.username_label),
+        password_label: text(response.password_label),
+        saml_method,
+        saml_request,
+        region: text(response.region),
+        panos_version: text(response.panos_version),
+    })
+}
+
+fn text(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_saml_prelogin() {
+        let request = BASE64_STANDARD.encode("https://idp.example.com/saml?SAMLRequest=abc");
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8" ?>
+            <prelogin-response>
+                <status>Success</status>
+                <ccusername/>
+                <autosubmit>false</autosubmit>
+                <msg/>
+                <newmsg/>
+                <authentication-message>Enter login credentials</authentication-message>
+                <username-label>Username</username-label>
+                <password-label>Password</password-label>
+                <panos-version>1</panos-version>
+                <saml-auth-method>REDIRECT</saml-auth-method>
+                <saml-request>{}</saml-request>
+                <region>CN</region>
+            </prelogin-response>"#,
+            request
+        );
+
+        let prelogin = parse_prelogin(&xml, PreloginTarget::Portal).unwrap();
+        assert_eq!(prelogin.region.as_deref(), Some("CN"));
+        assert_eq!(
+            prelogin.auth_method(),
+            AuthMethod::Saml {
+                method: SamlMethod::Redirect,
+                request: "https://idp.example.com/saml?SAMLRequest=abc".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_password_and_certificate_prelogin() {
+        let xml = r#"<prelogin-response>
+                <status>Success</status>
+                <authentication-message>Enter login credentials</authentication-message>
+                <username-label>Username</username-label>
+                <password-label>Password</password-label>
+                <panos-version>1</panos-version>
+            </prelogin-response>"#;
+        let prelogin = parse_prelogin(xml, PreloginTarget::Gateway).unwrap();
+        assert_eq!(prelogin.auth_method(), AuthMethod::Password);
+        assert_eq!(prelogin.username_label.as_deref(), Some("Username"));
+
+        let xml = r#"<prelogin-response><status>Success</status></prelogin-response>"#;
+        let prelogin = parse_prelogin(xml, PreloginTarget::Gateway).unwrap();
+        assert_eq!(prelogin.auth_method(), AuthMethod::Certificate);
+    }
+
+    #[test]
+    fn test_parse_prelogin_error() {
+        let xml = r#"<prelogin-response>
+                <status>Error</status>
+                <msg>Valid client certificate is required</msg>
+            </prelogin-response>"#;
+        let err = parse_prelogin(xml, PreloginTarget::Portal).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Valid client certificate is required"));
+    }
+}
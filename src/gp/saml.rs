@@ -0,0 +1,317 @@
+use super::credential::{Credentials, Secret};
+use super::prelogin::{AuthMethod, Prelogin, SamlMethod};
+use base64::prelude::*;
+
+const CALLBACK_SCHEME: &str = "globalprotectcallback:";
+
+/// 浏览器在 SAML 认证过程中观察到的内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SamlObservation {
+    /// 响应头, 网关在 SAML 完成后通过 saml-username / prelogin-cookie 等头下发结果
+    Header(String, String),
+    /// 页面内容, 结果也以 `<!-- <saml-username>..</saml-username> -->` 的形式嵌在页面中
+    Html(String),
+    /// 浏览器跳转的 URL, 新版本网关会跳转到 globalprotectcallback:
+    Url(String),
+}
+
+/// SAML 认证得到的凭据
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SamlResult {
+    pub username: String,
+    pub prelogin_cookie: Option<String>,
+    pub portal_userauthcookie: Option<String>,
+}
+
+impl SamlResult {
+    /// 交给 login.esp 或门户 getconfig 的凭据, 优先使用 prelogin-cookie
+    pub fn credentials(&self) -> Option<Credentials> {
+        let secret = match (&self.prelogin_cookie, &self.portal_userauthcookie) {
+            (Some(cookie), _) => Secret::PreloginCookie(cookie.clone()),
+            (None, Some(cookie)) => Secret::PortalUserAuthCookie(cookie.clone()),
+            (None, None) => return None,
+        };
+        Some(Credentials::new(&self.username, secret))
+    }
+}
+
+/// 从浏览器观察到的内容中收集 SAML 结果
+#[derive(Debug, Default)]
+pub struct SamlWatcher {
+    username: Option<String>,
+    prelogin_cookie: Option<String>,
+    portal_userauthcookie: Option<String>,
+    status: Option<String>,
+}
+
+impl SamlWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, observation: SamlObservation) {
+        match observation {
+            SamlObservation::Header(name, value) => self.set(&name.to_ascii_lowercase(), &value),
+            SamlObservation::Html(html) => {
+                for name in [
+                    "saml-auth-status",
+                    "saml-username",
+                    "prelogin-cookie",
+                    "portal-userauthcookie",
+                ] {
+                    if let Some(value) = tag_value(&html, name) {
+                        self.set(name, &value);
+                    }
+                }
+            }
+            SamlObservation::Url(url) => {
+                if let Some(data) = url.strip_prefix(CALLBACK_SCHEME) {
+                    self.observe_callback(data);
+                }
+            }
+        }
+    }
+
+    /// globalprotectcallback: 后面可能是 `un=..&token=..` 形式的参数,
+    /// 也可能是 base64 编码的结果页面
+    fn observe_callback(&mut self, data: &str) {
+        let data = data.trim_start_matches('/');
+        if data.contains("un=") {
+            for pair in data.split('&') {
+                match pair.split_once('=') {
+                    Some(("un", v)) => self.set("saml-username", &percent_decode(v)),
+                    Some(("token", v)) => self.set("prelogin-cookie", &percent_decode(v)),
+                    _ => {}
+                }
+            }
+            return;
+        }
+        match BASE64_STANDARD.decode(data.trim()) {
+            Ok(bytes) => self.observe(SamlObservation::Html(
+                String::from_utf8_lossy(&bytes).into_owned(),
+            )),
+            Err(e) => log::warn!("unrecognized globalprotectcallback data: {}", e),
+        }
+    }
+
+    fn set(&mut self, name: &str, value: &str) {
+        let value = value.trim();
+        if value.is_empty() {
+            return;
+        }
+        let slot = match name {
+            "saml-username" => &mut self.username,
+            "prelogin-cookie" => &mut self.prelogin_cookie,
+            "portal-userauthcookie" => &mut self.portal_userauthcookie,
+            "saml-auth-status" => &mut self.status,
+            _ => return,
+        };
+        *slot = Some(value.to_string());
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.failed()
+            || (self.username.is_some()
+                && (self.prelogin_cookie.is_some() || self.portal_userauthcookie.is_some()))
+    }
+
+    /// saml-auth-status 为 -1 表示 IdP 认证失败
+    pub fn failed(&self) -> bool {
+        self.status.as_deref() == Some("-1")
+    }
+
+    pub fn into_result(self) -> Result<SamlResult, Box<dyn std::error::Error>> {
+        if self.failed() {
+            return Err("SAML authentication failed".into());
+        }
+        let username = self
+            .username
+            .ok_or("SAML authentication did not return saml-username")?;
+        if self.prelogin_cookie.is_none() && self.portal_userauthcookie.is_none() {
+            return Err("SAML authentication did not return a cookie".into());
+        }
+        Ok(SamlResult {
+            username,
+            prelogin_cookie: self.prelogin_cookie,
+            portal_userauthcookie: self.portal_userauthcookie,
+        })
+    }
+}
+
+/// 执行 SAML 请求的浏览器
+///
+/// 实现者打开 `request` 并把观察到的响应头, 页面和跳转交给 `watcher`,
+/// 直到 `watcher.is_done()` 或用户放弃.
+pub trait SamlBrowser {
+    fn authenticate(
+        &mut self,
+        method: SamlMethod,
+        request: &str,
+        watcher: &mut SamlWatcher,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// 使用 prelogin 下发的 SAML 请求完成认证
+pub fn saml_login(
+    browser: &mut dyn SamlBrowser,
+    prelogin: &Prelogin,
+) -> Result<SamlResult, Box<dyn std::error::Error>> {
+    let (method, request) = match prelogin.auth_method() {
+        AuthMethod::Saml { method, request } => (method, request),
+        other => return Err(format!("server does not use SAML: {:?}", other).into()),
+    };
+
+    let mut watcher = SamlWatcher::new();
+    browser.authenticate(method, &request, &mut watcher)?;
+    watcher.into_result()
+}
+
+fn tag_value(html: &str, name: &str) -> Option<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = html.find(&open)? + open.len();
+    let end = start + html[start..].find(&close)?;
+    Some(html[start..end].to_string())
+}
+
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(b) => {
+                        out.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gp::prelogin::PreloginTarget;
+
+    /// 回放预先录制的 SAML 过程
+    struct ReplayBrowser {
+        observations: Vec<SamlObservation>,
+    }
+
+    impl SamlBrowser for ReplayBrowser {
+        fn authenticate(
+            &mut self,
+            method: SamlMethod,
+            request: &str,
+            watcher: &mut SamlWatcher,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            assert_eq!(method, SamlMethod::Post);
+            assert!(request.contains("SAMLRequest"));
+            for observation in self.observations.drain(..) {
+                watcher.observe(observation);
+                if watcher.is_done() {
+                    return Ok(());
+                }
+            }
+            Err("browser closed before SAML completed".into())
+        }
+    }
+
+    fn saml_prelogin() -> Prelogin {
+        Prelogin {
+            target: PreloginTarget::Portal,
+            auth_message: None,
+            username_label: None,
+            password_label: None,
+            saml_method: Some(SamlMethod::Post),
+            saml_request: Some(
+                r#"<form method="POST" action="https://idp.example.com/sso"><input name="SAMLRequest" value="abc"/></form>"#
+                    .to_string(),
+            ),
+            region: None,
+            panos_version: None,
+        }
+    }
+
+    #[test]
+    fn test_saml_login_from_html_comment() {
+        let mut browser = ReplayBrowser {
+            observations: vec![
+                SamlObservation::Url("https://idp.example.com/sso".to_string()),
+                SamlObservation::Html("<html><body>Sign in</body></html>".to_string()),
+                SamlObservation::Html(
+                    "<html><!-- <saml-auth-status>1</saml-auth-status>\
+                     <prelogin-cookie>PRELOGIN123</prelogin-cookie>\
+                     <saml-username>alice@example.com</saml-username>\
+                     <saml-slo>no</saml-slo> --></html>"
+                        .to_string(),
+                ),
+            ],
+        };
+
+        let result = saml_login(&mut browser, &saml_prelogin()).unwrap();
+        assert_eq!(result.username, "alice@example.com");
+        assert_eq!(
+            result.credentials().unwrap().secret,
+            Secret::PreloginCookie("PRELOGIN123".to_string())
+        );
+    }
+
+    #[test]
+    fn test_saml_login_from_headers_and_callback() {
+        let mut browser = ReplayBrowser {
+            observations: vec![
+                SamlObservation::Header("Saml-Username".to_string(), "bob".to_string()),
+                SamlObservation::Header(
+                    "Portal-UserAuthCookie".to_string(),
+                    "PORTALCOOKIE".to_string(),
+                ),
+            ],
+        };
+        let result = saml_login(&mut browser, &saml_prelogin()).unwrap();
+        assert_eq!(
+            result.portal_userauthcookie.as_deref(),
+            Some("PORTALCOOKIE")
+        );
+
+        let mut watcher = SamlWatcher::new();
+        watcher.observe(SamlObservation::Url(
+            "globalprotectcallback:cas-as=1&un=carol%40example.com&token=T0KEN".to_string(),
+        ));
+        let result = watcher.into_result().unwrap();
+        assert_eq!(result.username, "carol@example.com");
+        assert_eq!(result.prelogin_cookie.as_deref(), Some("T0KEN"));
+
+        let page = "<saml-username>dave</saml-username><prelogin-cookie>C00KIE</prelogin-cookie>";
+        let mut watcher = SamlWatcher::new();
+        watcher.observe(SamlObservation::Url(format!(
+            "globalprotectcallback:{}",
+            BASE64_STANDARD.encode(page)
+        )));
+        assert!(watcher.is_done());
+        assert_eq!(watcher.into_result().unwrap().username, "dave");
+    }
+
+    #[test]
+    fn test_saml_login_failure() {
+        let mut browser = ReplayBrowser {
+            observations: vec![SamlObservation::Html(
+                "<!-- <saml-auth-status>-1</saml-auth-status> -->".to_string(),
+            )],
+        };
+        assert!(saml_login(&mut browser, &saml_prelogin()).is_err());
+    }
+}
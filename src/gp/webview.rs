@@ -0,0 +1,118 @@
+use super::prelogin::SamlMethod;
+use super::saml::{SamlBrowser, SamlObservation, SamlWatcher};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tao::event::{Event, WindowEvent};
+use tao::event_loop::{ControlFlow, EventLoopBuilder};
+use tao::platform::run_return::EventLoopExtRunReturn;
+use tao::window::WindowBuilder;
+use wry::WebViewBuilder;
+
+/// 页面加载完成后把页面内容发回给 SAML watcher
+const REPORT_PAGE_SCRIPT: &str = r#"
+window.addEventListener("load", function () {
+    window.ipc.postMessage(document.documentElement.outerHTML);
+});
+"#;
+
+enum UserEvent {
+    Done,
+}
+
+/// 基于 wry/tao 的 SAML 浏览器窗口
+///
+/// wry 无法读取响应头, 因此依靠页面中的 `<saml-username>` 等注释和
+/// globalprotectcallback: 跳转获取认证结果.
+pub struct WebviewBrowser {
+    pub title: String,
+}
+
+impl Default for WebviewBrowser {
+    fn default() -> Self {
+        WebviewBrowser {
+            title: "GlobalProtect Login".to_string(),
+        }
+    }
+}
+
+impl SamlBrowser for WebviewBrowser {
+    fn authenticate(
+        &mut self,
+        method: SamlMethod,
+        request: &str,
+        watcher: &mut SamlWatcher,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+        let mut builder = EventLoopBuilder::<UserEvent>::with_user_event();
+        #[cfg(target_os = "linux")]
+        {
+            use tao::platform::unix::EventLoopBuilderExtUnix;
+            builder.with_any_thread(true);
+        }
+        let mut event_loop = builder.build();
+        let window = WindowBuilder::new()
+            .with_title(&self.title)
+            .build(&event_loop)?;
+
+        let shared = Rc::new(RefCell::new(std::mem::take(watcher)));
+
+        let nav_watcher = Rc::clone(&shared);
+        let nav_proxy = event_loop.create_proxy();
+        let ipc_watcher = Rc::clone(&shared);
+        let ipc_proxy = event_loop.create_proxy();
+
+        let builder = WebViewBuilder::new()
+            .with_initialization_script(REPORT_PAGE_SCRIPT)
+            .with_navigation_handler(move |url| {
+                let callback = url.starts_with("globalprotectcallback:");
+                let mut watcher = nav_watcher.borrow_mut();
+                watcher.observe(SamlObservation::Url(url));
+                if watcher.is_done() {
+                    let _ = nav_proxy.send_event(UserEvent::Done);
+                }
+                // 不让 webview 去打开 globalprotectcallback: 协议
+                !callback
+            })
+            .with_ipc_handler(move |request| {
+                let mut watcher = ipc_watcher.borrow_mut();
+                watcher.observe(SamlObservation::Html(request.into_body()));
+                if watcher.is_done() {
+                    let _ = ipc_proxy.send_event(UserEvent::Done);
+                }
+            });
+        let builder = match method {
+            SamlMethod::Redirect => builder.with_url(request),
+            SamlMethod::Post => builder.with_html(request),
+        };
+
+        #[cfg(not(target_os = "linux"))]
+        let webview = builder.build(&window)?;
+        #[cfg(target_os = "linux")]
+        let webview = {
+            use tao::platform::unix::WindowExtUnix;
+            use wry::WebViewBuilderExtUnix;
+            let vbox = window.default_vbox().ok_or("window has no GTK container")?;
+            builder.build_gtk(vbox)?
+        };
+
+        event_loop.run_return(|event, _, control_flow| {
+            *control_flow = ControlFlow::Wait;
+            match event {
+                Event::UserEvent(UserEvent::Done) => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    ..
+                } => *control_flow = ControlFlow::Exit,
+                _ => {}
+            }
+        });
+
+        drop(webview);
+        drop(window);
+        *watcher = std::mem::take(&mut *shared.borrow_mut());
+        if !watcher.is_done() {
+            return Err("SAML window closed before authentication completed".into());
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 pub mod core;
 pub mod libs;
 pub mod utils;
-pub mod gp;
\ No newline at end of file
+pub mod gp;
+pub mod net;
+pub mod tunnel;
\ No newline at end of file
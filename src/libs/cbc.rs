@@ -1,15 +1,81 @@
-use libaes::Cipher;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256, Block};
 
-pub fn encrypt(key: &[u8; 16], plaintext: &[u8], iv: &[u8]) -> Vec<u8> {
-    let cipher = Cipher::new_128(key);
-    let encrypted = cipher.cbc_encrypt(iv, plaintext);
-    encrypted
+const BLOCK_SIZE: usize = 16;
+
+/// 展开好密钥的 AES-CBC, 可以在多个包之间复用. ESP 自己负责填充, 这里不做 PKCS#7 填充
+#[derive(Clone)]
+pub enum Cipher {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 不打印密钥
+        match self {
+            Cipher::Aes128(_) => f.write_str("Cipher::Aes128"),
+            Cipher::Aes256(_) => f.write_str("Cipher::Aes256"),
+        }
+    }
+}
+
+impl Cipher {
+    /// 根据密钥长度选择 AES-128 或 AES-256
+    pub fn new(key: &[u8]) -> Result<Self, String> {
+        match key.len() {
+            16 => Ok(Cipher::Aes128(Box::new(
+                Aes128::new_from_slice(key).unwrap(),
+            ))),
+            32 => Ok(Cipher::Aes256(Box::new(
+                Aes256::new_from_slice(key).unwrap(),
+            ))),
+            n => Err(format!("invalid AES-CBC key length: {}", n)),
+        }
+    }
+
+    /// 原地加密, `data` 的长度必须是 16 的倍数
+    pub fn encrypt_in_place(&self, iv: &[u8], data: &mut [u8]) {
+        assert!(data.len().is_multiple_of(BLOCK_SIZE), "unaligned CBC data");
+        let mut prev = *Block::from_slice(iv);
+        for chunk in data.chunks_exact_mut(BLOCK_SIZE) {
+            let block = Block::from_mut_slice(chunk);
+            block.iter_mut().zip(prev.iter()).for_each(|(b, p)| *b ^= p);
+            match self {
+                Cipher::Aes128(aes) => aes.encrypt_block(block),
+                Cipher::Aes256(aes) => aes.encrypt_block(block),
+            }
+            prev = *block;
+        }
+    }
+
+    /// 原地解密, `data` 的长度必须是 16 的倍数
+    pub fn decrypt_in_place(&self, iv: &[u8], data: &mut [u8]) {
+        assert!(data.len().is_multiple_of(BLOCK_SIZE), "unaligned CBC data");
+        let mut prev = *Block::from_slice(iv);
+        for chunk in data.chunks_exact_mut(BLOCK_SIZE) {
+            let block = Block::from_mut_slice(chunk);
+            let ciphertext = *block;
+            match self {
+                Cipher::Aes128(aes) => aes.decrypt_block(block),
+                Cipher::Aes256(aes) => aes.decrypt_block(block),
+            }
+            block.iter_mut().zip(prev.iter()).for_each(|(b, p)| *b ^= p);
+            prev = ciphertext;
+        }
+    }
 }
 
-pub fn decrypt(key: &[u8; 16], ciphertext: &[u8], iv: &[u8]) -> Vec<u8> {
-    let cipher = Cipher::new_128(key);
-    let decrypted = cipher.cbc_decrypt(iv, ciphertext);
-    decrypted
+pub fn encrypt(key: &[u8], plaintext: &[u8], iv: &[u8]) -> Vec<u8> {
+    let mut data = plaintext.to_vec();
+    Cipher::new(key).unwrap().encrypt_in_place(iv, &mut data);
+    data
+}
+
+pub fn decrypt(key: &[u8], ciphertext: &[u8], iv: &[u8]) -> Vec<u8> {
+    let mut data = ciphertext.to_vec();
+    Cipher::new(key).unwrap().decrypt_in_place(iv, &mut data);
+    data
 }
 
 #[cfg(test)]
@@ -19,12 +85,47 @@ mod tests {
     #[test]
     fn test_encrypt_decrypt() {
         let key = b"1234567890123456";
-        let plaintext = b"Hello, world!";
+        let plaintext = b"Hello, world!\x01\x01\x02";
         let iv = b"0123456789abcdef";
 
         let ciphertext = encrypt(key, plaintext, iv);
+        assert_eq!(ciphertext.len(), plaintext.len());
         let decrypted = decrypt(key, &ciphertext, iv);
 
         assert_eq!(plaintext, &decrypted[..]);
     }
+
+    #[test]
+    fn test_aes_256_cbc() {
+        // NIST SP 800-38A F.2.5 CBC-AES256.Encrypt, 第一个分组
+        let key = hex::decode("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4")
+            .unwrap();
+        let iv = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let plaintext = hex::decode("6bc1bee22e409f96e93d7e117393172a").unwrap();
+
+        let ciphertext = encrypt(&key, &plaintext, &iv);
+        assert_eq!(hex::encode(&ciphertext), "f58c4c04d6e5f1ba779eabfb5f7bfbd6");
+        assert_eq!(decrypt(&key, &ciphertext, &iv), plaintext);
+    }
+
+    #[test]
+    fn test_in_place_chaining() {
+        // NIST SP 800-38A F.2.1 CBC-AES128.Encrypt, 前两个分组
+        let cipher =
+            Cipher::new(&hex::decode("2b7e151628aed2a6abf7158809cf4f3c").unwrap()).unwrap();
+        let iv = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let plaintext =
+            hex::decode("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51")
+                .unwrap();
+
+        let mut data = plaintext.clone();
+        cipher.encrypt_in_place(&iv, &mut data);
+        assert_eq!(
+            hex::encode(&data),
+            "7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2"
+        );
+        cipher.decrypt_in_place(&iv, &mut data);
+        assert_eq!(data, plaintext);
+        assert!(Cipher::new(&[0u8; 24]).is_err());
+    }
 }
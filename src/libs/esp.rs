@@ -1,28 +1,412 @@
-use super::cbc;
-use super::hmacsha1;
+use super::replay::{ReplayError, ReplayStats, ReplayWindow};
+use super::suite::{new_suite, CipherSuite, GCM_SALT_LEN};
 use rand::RngCore;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
+/// 出站序号超过这个值后 `needs_rekey` 返回 true, 留出足够余量在序号用完前重新获取密钥
+pub const REKEY_THRESHOLD: u32 = u32::MAX - (1 << 24);
+
+/// 网关在 getconfig 中协商的加密算法 (enc-algo)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncAlgo {
+    Aes128Cbc,
+    Aes256Cbc,
+    /// RFC 4106, 自带完整性校验, 不使用 hmac-algo
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl EncAlgo {
+    /// 网关下发的 ekey 长度, GCM 的密钥末尾带 4 字节 salt
+    pub fn key_len(&self) -> usize {
+        match self {
+            EncAlgo::Aes128Cbc => 16,
+            EncAlgo::Aes256Cbc => 32,
+            EncAlgo::Aes128Gcm => 16 + GCM_SALT_LEN,
+            EncAlgo::Aes256Gcm => 32 + GCM_SALT_LEN,
+        }
+    }
+
+    pub fn is_aead(&self) -> bool {
+        matches!(self, EncAlgo::Aes128Gcm | EncAlgo::Aes256Gcm)
+    }
+}
+
+impl FromStr for EncAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-128-cbc" | "aes128" => Ok(EncAlgo::Aes128Cbc),
+            "aes-256-cbc" | "aes256" => Ok(EncAlgo::Aes256Cbc),
+            "aes-128-gcm" => Ok(EncAlgo::Aes128Gcm),
+            "aes-256-gcm" => Ok(EncAlgo::Aes256Gcm),
+            _ => Err(format!("unsupported encryption algorithm: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EncAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncAlgo::Aes128Cbc => write!(f, "aes-128-cbc"),
+            EncAlgo::Aes256Cbc => write!(f, "aes-256-cbc"),
+            EncAlgo::Aes128Gcm => write!(f, "aes-128-gcm"),
+            EncAlgo::Aes256Gcm => write!(f, "aes-256-gcm"),
+        }
+    }
+}
+
+/// 网关在 getconfig 中协商的认证算法 (hmac-algo)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HmacAlgo {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HmacAlgo {
+    /// akey 长度, 等于摘要长度
+    pub fn key_len(&self) -> usize {
+        match self {
+            HmacAlgo::Sha1 => 20,
+            HmacAlgo::Sha256 => 32,
+            HmacAlgo::Sha384 => 48,
+            HmacAlgo::Sha512 => 64,
+        }
+    }
+
+    /// 截断后的 ICV 长度, SHA-1 为 96 位 (RFC 2404), SHA-2 为摘要的一半 (RFC 4868)
+    pub fn icv_len(&self) -> usize {
+        match self {
+            HmacAlgo::Sha1 => 12,
+            _ => self.key_len() / 2,
+        }
+    }
+}
+
+impl FromStr for HmacAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha1" => Ok(HmacAlgo::Sha1),
+            "sha256" => Ok(HmacAlgo::Sha256),
+            "sha384" => Ok(HmacAlgo::Sha384),
+            "sha512" => Ok(HmacAlgo::Sha512),
+            _ => Err(format!("unsupported hmac algorithm: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for HmacAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HmacAlgo::Sha1 => write!(f, "sha1"),
+            HmacAlgo::Sha256 => write!(f, "sha256"),
+            HmacAlgo::Sha384 => write!(f, "sha384"),
+            HmacAlgo::Sha512 => write!(f, "sha512"),
+        }
+    }
+}
+
+/// ESP 尾部的下一个头部字段, 隧道模式下是内层包的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextHeader {
+    /// IPPROTO_IPIP
+    Ipv4,
+    /// IPPROTO_IPV6
+    Ipv6,
+}
+
+impl NextHeader {
+    /// 根据 IP 头的版本号判断内层包的协议
+    pub fn of_packet(packet: &[u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 => Some(NextHeader::Ipv4),
+            6 => Some(NextHeader::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn from_protocol(protocol: u8) -> Option<Self> {
+        match protocol {
+            4 => Some(NextHeader::Ipv4),
+            41 => Some(NextHeader::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        match self {
+            NextHeader::Ipv4 => 4,
+            NextHeader::Ipv6 => 41,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspError {
+    /// 32 位序号已经用完, 不允许回绕, 必须重新协商密钥 (RFC 4303 3.3.3)
+    SequenceExhausted,
+    /// 防重放窗口内已经收到过这个序号
+    Replayed(u32),
+    /// 序号落在防重放窗口之外
+    TooOld(u32),
+    /// ICV 校验失败
+    Authentication,
+    /// 包长度不足以容纳 ESP 头, IV, 一个加密块和 ICV
+    TooShort { len: usize, min: usize },
+    /// 去掉 ICV 后的密文长度没有按块对齐, 通常说明 ICV 长度不对
+    Misaligned { len: usize, block_size: usize },
+    /// SPI 不是这个 SA 的入站 SPI
+    UnknownSpi(u32),
+    /// 填充长度超出解密后的载荷
+    PadLength(u8),
+    /// 填充内容不是 RFC 4303 规定的 1, 2, 3, ...
+    Padding,
+    /// 下一个头部既不是 IPv4 也不是 IPv6, 包括 RFC 4303 的填充包 (59)
+    NextHeader(u8),
+    /// 待封装的数据不是 IPv4 或 IPv6 包
+    NotIp,
+    /// 原地封装的缓冲区放不下 headroom, 内层包和 tailroom
+    NoRoom { len: usize, needed: usize },
+}
+
+impl From<ReplayError> for EspError {
+    fn from(e: ReplayError) -> Self {
+        match e {
+            ReplayError::Replayed(seq) => EspError::Replayed(seq),
+            ReplayError::TooOld(seq) => EspError::TooOld(seq),
+        }
+    }
+}
+
+impl fmt::Display for EspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EspError::SequenceExhausted => {
+                write!(f, "ESP sequence number exhausted, rekey required")
+            }
+            EspError::Replayed(seq) => write!(f, "replayed ESP packet, seq {}", seq),
+            EspError::TooOld(seq) => write!(f, "ESP packet outside replay window, seq {}", seq),
+            EspError::Authentication => write!(f, "ESP integrity check failed"),
+            EspError::TooShort { len, min } => {
+                write!(
+                    f,
+                    "ESP packet too short: {} bytes, need at least {}",
+                    len, min
+                )
+            }
+            EspError::Misaligned { len, block_size } => write!(
+                f,
+                "ESP ciphertext length {} is not a multiple of {}",
+                len, block_size
+            ),
+            EspError::UnknownSpi(spi) => write!(f, "unexpected ESP SPI {:#010x}", spi),
+            EspError::PadLength(len) => write!(f, "invalid ESP pad length {}", len),
+            EspError::Padding => write!(f, "invalid ESP padding bytes"),
+            EspError::NextHeader(protocol) => {
+                write!(f, "unsupported ESP next header {}", protocol)
+            }
+            EspError::NotIp => write!(f, "payload is not an IPv4 or IPv6 packet"),
+            EspError::NoRoom { len, needed } => {
+                write!(f, "ESP buffer too small: {} bytes, need {}", len, needed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EspError {}
+
+/// 一个方向的 ESP SA
 #[derive(Debug)]
 pub struct ESP {
-    seq: u32,
+    /// 下一个出站序号, 用 64 位计数以便发现 32 位序号用完而不是回绕
+    next_seq: AtomicU64,
     spi: u32,
-    enc_key: [u8; 16],
-    mac_key: [u8; 20],
-    iv: [u8; 16],
+    enc_algo: EncAlgo,
+    suite: Box<dyn CipherSuite>,
+    /// 入站方向的防重放窗口
+    replay: Mutex<ReplayWindow>,
 }
 
 impl ESP {
-    pub fn new(seq: u32, spi: u32, enc_key: [u8; 16], mac_key: [u8; 20]) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut iv = [0u8; 16];
-        rng.fill_bytes(&mut iv);
-        ESP {
-            seq,
+    /// `seq` 是第一个出站包的序号, 通常为 1.
+    /// `enc_key` 和 `mac_key` 是网关下发的 ekey / akey, AEAD 算法没有 hmac, 忽略 `mac_key`
+    pub fn new(
+        seq: u32,
+        spi: u32,
+        enc_algo: EncAlgo,
+        hmac_algo: Option<HmacAlgo>,
+        enc_key: &[u8],
+        mac_key: &[u8],
+    ) -> Result<Self, String> {
+        let suite = new_suite(enc_algo, hmac_algo, enc_key, mac_key)?;
+        Ok(ESP {
+            next_seq: AtomicU64::new(seq as u64),
             spi,
-            enc_key,
-            mac_key,
-            iv,
+            enc_algo,
+            suite,
+            replay: Mutex::new(ReplayWindow::default()),
+        })
+    }
+
+    /// 设置入站防重放窗口的大小, 取值 64 到 1024
+    pub fn with_replay_window(self, size: u32) -> Result<Self, String> {
+        *self.replay.lock().unwrap() = ReplayWindow::new(size)?;
+        Ok(self)
+    }
+
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.replay.lock().unwrap().stats()
+    }
+
+    pub fn enc_algo(&self) -> EncAlgo {
+        self.enc_algo
+    }
+
+    pub fn spi(&self) -> u32 {
+        self.spi
+    }
+
+    /// 原地封装时内层包之前需要预留的字节数: ESP 头和 IV
+    pub fn headroom(&self) -> usize {
+        8 + self.suite.iv_len()
+    }
+
+    /// 原地封装时内层包之后最多需要的字节数: 填充, 填充长度, 下一个头部和 ICV
+    pub fn tailroom(&self) -> usize {
+        self.suite.block_size() + 1 + self.suite.icv_len()
+    }
+
+    /// 把一个 IP 包封装成 ESP 包, 每个包使用新的序号和 IV, 可以在多个任务间共享调用
+    pub fn encapsulate(&self, payload: &[u8]) -> Result<Vec<u8>, EspError> {
+        let headroom = self.headroom();
+        let mut buf = vec![0u8; headroom + payload.len() + self.tailroom()];
+        buf[headroom..headroom + payload.len()].copy_from_slice(payload);
+        let len = self.encapsulate_in_place(&mut buf, payload.len())?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// 在调用者的缓冲区中原地封装, 不分配内存.
+    ///
+    /// 内层包放在 `buf[headroom()..headroom() + payload_len]`, 其后至少留出 `tailroom()` 字节.
+    /// 封装好的 ESP 包从 `buf[0]` 开始, 返回它的长度.
+    pub fn encapsulate_in_place(
+        &self,
+        buf: &mut [u8],
+        payload_len: usize,
+    ) -> Result<usize, EspError> {
+        let headroom = self.headroom();
+        let needed = headroom + payload_len + self.tailroom();
+        if buf.len() < needed {
+            return Err(EspError::NoRoom {
+                len: buf.len(),
+                needed,
+            });
         }
+        let (head, rest) = buf.split_at_mut(headroom);
+        let next_header = NextHeader::of_packet(&rest[..payload_len]).ok_or(EspError::NotIp)?;
+        let seq = self.next_seq()?;
+
+        head[..4].copy_from_slice(&self.spi.to_be_bytes());
+        head[4..8].copy_from_slice(&seq.to_be_bytes());
+        let (header, iv) = head.split_at_mut(8);
+        self.fill_iv(seq, iv);
+
+        let data_len = payload_len + trailer_len(payload_len, self.suite.block_size());
+        let (data, rest) = rest.split_at_mut(data_len);
+        write_trailer(&mut data[payload_len..], next_header);
+        let icv = &mut rest[..self.suite.icv_len()];
+        self.suite
+            .seal_in_place(&header_bytes(header), iv, data, icv);
+        Ok(headroom + data_len + icv.len())
+    }
+
+    fn next_seq(&self) -> Result<u32, EspError> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        u32::try_from(seq).map_err(|_| EspError::SequenceExhausted)
+    }
+
+    /// CBC 要求 IV 不可预测, 每个包随机生成;
+    /// GCM 只要求 IV 在同一密钥下不重复, 直接使用不会回绕的序号
+    fn fill_iv(&self, seq: u32, iv: &mut [u8]) {
+        match self.enc_algo.is_aead() {
+            true => iv.copy_from_slice(&(seq as u64).to_be_bytes()),
+            false => rand::thread_rng().fill_bytes(iv),
+        }
+    }
+
+    /// 检查入站包的长度, 对齐和 SPI, 返回序号
+    fn parse_header(&self, packet: &[u8]) -> Result<u32, EspError> {
+        let block_size = self.suite.block_size();
+        // 至少要有一个加密块装下填充长度和下一个头部
+        let min = self.headroom() + block_size + self.suite.icv_len();
+        if packet.len() < min {
+            return Err(EspError::TooShort {
+                len: packet.len(),
+                min,
+            });
+        }
+        let data_len = packet.len() - self.headroom() - self.suite.icv_len();
+        if !data_len.is_multiple_of(block_size) {
+            return Err(EspError::Misaligned {
+                len: data_len,
+                block_size,
+            });
+        }
+
+        let spi = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        if spi != self.spi {
+            return Err(EspError::UnknownSpi(spi));
+        }
+        Ok(u32::from_be_bytes([
+            packet[4], packet[5], packet[6], packet[7],
+        ]))
+    }
+
+    /// 解开一个入站 ESP 包, 校验 ICV 和防重放窗口后返回内层 IP 包
+    pub fn decapsulate(&self, packet: &[u8]) -> Result<Vec<u8>, EspError> {
+        let mut buf = packet.to_vec();
+        let payload = self.decapsulate_in_place(&mut buf)?;
+        buf.truncate(payload.end);
+        buf.drain(..payload.start);
+        Ok(buf)
+    }
+
+    /// 在收到的包上原地解密, 不分配内存, 返回内层包在 `packet` 中的位置
+    pub fn decapsulate_in_place(&self, packet: &mut [u8]) -> Result<Range<usize>, EspError> {
+        let seq = self.parse_header(packet)?;
+        // 先用窗口快速丢弃重放的包, ICV 通过后才更新窗口
+        self.replay.lock().unwrap().check(seq)?;
+
+        let headroom = self.headroom();
+        let data_len = packet.len() - headroom - self.suite.icv_len();
+        let (head, rest) = packet.split_at_mut(headroom);
+        let (data, icv) = rest.split_at_mut(data_len);
+        let (header, iv) = head.split_at(8);
+        if !self
+            .suite
+            .open_in_place(&header_bytes(header), iv, data, icv)
+        {
+            return Err(EspError::Authentication);
+        }
+        let (payload_len, _) = read_trailer(data)?;
+
+        self.replay.lock().unwrap().accept(seq)?;
+        Ok(headroom..headroom + payload_len)
+    }
+
+    /// 出站序号即将用完, 调用者应当重新 getconfig 获取新的密钥
+    pub fn needs_rekey(&self) -> bool {
+        self.next_seq.load(Ordering::Relaxed) > REKEY_THRESHOLD as u64
     }
 }
 
@@ -30,35 +414,23 @@ impl ESP {
 pub struct ESPPacket {
     spi: u32,
     seq: u32,
-    iv: [u8; 16],
+    iv: Vec<u8>,
     pub data: Vec<u8>,
-    hmac: [u8; 12],
+    icv: Vec<u8>,
 }
 
 impl ESPPacket {
-    pub fn new(esp: &ESP, data: Vec<u8>) -> Self {
-        ESPPacket {
-            spi: esp.spi,
-            seq: esp.seq,
-            iv: esp.iv,
-            data,
-            hmac: [0u8; 12],
-        }
-    }
-
-    pub fn from_bytes(bytesdata: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        let spi = u32::from_be_bytes(bytesdata[0..4].try_into().unwrap());
-        let seq = u32::from_be_bytes(bytesdata[4..8].try_into().unwrap());
-        let iv = bytesdata[8..24].try_into().unwrap();
-        let hmac = bytesdata[bytesdata.len() - 12..].try_into().unwrap();
-        let data = bytesdata[24..bytesdata.len() - 12].to_vec();
-
+    /// 解析收到的 ESP 包, 只检查长度, 对齐和 SPI, 不做解密
+    pub fn from_bytes(esp: &ESP, bytesdata: &[u8]) -> Result<Self, EspError> {
+        let seq = esp.parse_header(bytesdata)?;
+        let (head, rest) = bytesdata.split_at(esp.headroom());
+        let (data, icv) = rest.split_at(rest.len() - esp.suite.icv_len());
         Ok(ESPPacket {
-            spi,
+            spi: esp.spi,
             seq,
-            iv,
-            data,
-            hmac,
+            iv: head[8..].to_vec(),
+            data: data.to_vec(),
+            icv: icv.to_vec(),
         })
     }
 
@@ -68,92 +440,521 @@ impl ESPPacket {
         packet.extend_from_slice(&self.seq.to_be_bytes());
         packet.extend_from_slice(&self.iv);
         packet.extend_from_slice(&self.data);
-        packet.extend_from_slice(&self.hmac);
+        packet.extend_from_slice(&self.icv);
         packet
     }
 
-    pub fn encrypt(&mut self, esp: &ESP) -> Result<(), Box<dyn std::error::Error>> {
-        let blocksize = 16;
-
-        // 填充数据
-        let padding_len = blocksize - 1 - (self.data.len() % blocksize);
-        for i in 1..padding_len {
-            self.data.push(i as u8);
-        }
-        self.data.push((padding_len - 1) as u8);
+    /// ESP 头, 参与 ICV 计算
+    fn header(&self) -> [u8; 8] {
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&self.spi.to_be_bytes());
+        header[4..].copy_from_slice(&self.seq.to_be_bytes());
+        header
+    }
 
-        let nexthdr: u8 = 0x04;
-        self.data.push(nexthdr);
+    /// 只校验 ICV 并解密, 不经过防重放窗口, 收包应当使用 `ESP::decapsulate`.
+    /// 成功后 `data` 只剩内层包, 返回尾部的下一个头部
+    pub fn decrypt(&mut self, esp: &ESP) -> Result<NextHeader, EspError> {
+        let mut data = esp
+            .suite
+            .open(&self.header(), &self.iv, &self.data, &self.icv)
+            .ok_or(EspError::Authentication)?;
 
-        // 加密数据 (AES-128-CBC)
-        let data = cbc::encrypt(&esp.enc_key, &self.data, &esp.iv);
+        let (payload_len, next_header) = read_trailer(&data)?;
+        data.truncate(payload_len);
         self.data = data;
-
-        // 计算 HMAC
-        let hmac = hmacsha1::hmac_sha1(&esp.mac_key, &self.data);
-        self.hmac = hmac;
-
-        Ok(())
+        Ok(next_header)
     }
+}
 
-    pub fn decrypt(&mut self, esp: &ESP) -> Result<(), Box<dyn std::error::Error>> {
-        // 验证 HMAC
-        let hmac = self.hmac;
-        let data = &self.data;
-        let expected_hmac = hmacsha1::hmac_sha1(&esp.mac_key, data);
-        if expected_hmac != hmac {
-            return Err("HMAC verification failed".into());
-        }
+fn header_bytes(header: &[u8]) -> [u8; 8] {
+    header.try_into().expect("ESP header is 8 bytes")
+}
 
-        // 解密数据 (AES-128-CBC)
-        let data = cbc::decrypt(&esp.enc_key, data, &self.iv);
+/// 载荷之后 `填充 | 填充长度 | 下一个头部` 的总长度, 使整体按 `block_size` 对齐 (RFC 4303 2.4)
+fn trailer_len(payload_len: usize, block_size: usize) -> usize {
+    (block_size - (payload_len + 2) % block_size) % block_size + 2
+}
 
-        // 去除填充数据
-        let padding_len = data[data.len() - 1] as usize;
-        self.data = data[..data.len() - padding_len].to_vec();
+/// 写入长度为 `trailer_len` 的尾部, 填充内容为 1, 2, 3, ...
+fn write_trailer(trailer: &mut [u8], next_header: NextHeader) {
+    let pad_len = trailer.len() - 2;
+    for (i, b) in trailer[..pad_len].iter_mut().enumerate() {
+        *b = i as u8 + 1;
+    }
+    trailer[pad_len] = pad_len as u8;
+    trailer[pad_len + 1] = next_header.protocol();
+}
 
-        Ok(())
+/// 校验解密后的尾部, 返回载荷长度和下一个头部
+fn read_trailer(data: &[u8]) -> Result<(usize, NextHeader), EspError> {
+    let (pad_len, protocol) = match data {
+        [.., pad_len, protocol] => (*pad_len, *protocol),
+        _ => return Err(EspError::PadLength(0)),
+    };
+    let payload_len = data
+        .len()
+        .checked_sub(pad_len as usize + 2)
+        .ok_or(EspError::PadLength(pad_len))?;
+    let padding = &data[payload_len..data.len() - 2];
+    if padding
+        .iter()
+        .zip(1u8..)
+        .any(|(&b, expected)| b != expected)
+    {
+        return Err(EspError::Padding);
     }
+    let next_header = NextHeader::from_protocol(protocol).ok_or(EspError::NextHeader(protocol))?;
+    Ok((payload_len, next_header))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::libs::{cbc, gcm};
+
+    const DATA_HEX: &str =
+        "4500002cdc4c40004001a5480ac121010ac182b90000103c474702cd6d6f6e69746f72000070616e20686120";
+
+    /// ICMPv6 echo request 2001:db8::1 -> 2001:db8::2
+    const IPV6_HEX: &str = "6000000000103a4020010db8000000000000000000000001\
+                            20010db80000000000000000000000028000f6e900010001";
+
+    /// 按 RFC 4303 独立生成 (Python cryptography) 的 ESP 包, 密钥, SPI 和 IV 固定
+    struct Fixture {
+        enc_algo: EncAlgo,
+        hmac_algo: Option<HmacAlgo>,
+        enc_key: &'static str,
+        mac_key: &'static str,
+        spi: u32,
+        seq: u32,
+        payload: &'static str,
+        next_header: NextHeader,
+        wire: &'static str,
+    }
+
+    const FIXTURES: &[Fixture] = &[
+        Fixture {
+            enc_algo: EncAlgo::Aes128Cbc,
+            hmac_algo: Some(HmacAlgo::Sha1),
+            enc_key: "510f909f4014dfec78b3bb8c7cbe86ac",
+            mac_key: "678c7e80dd68ee69e1279da28054186de9ec113c",
+            spi: 0x6f77893a,
+            seq: 0x2a,
+            payload: DATA_HEX,
+            next_header: NextHeader::Ipv4,
+            wire:
+                "6f77893a0000002a8ce82eefbea0da3c44699ed7db51b7d9fe1b5d02bde8e2630dd2276b0039857a\
+                   469a097f037f41f4568b1b18b24ea812df8ba82b33fc28151b5175211ea6acb5e9d5017cdc7e8b2a\
+                   45951c86",
+        },
+        Fixture {
+            enc_algo: EncAlgo::Aes256Cbc,
+            hmac_algo: Some(HmacAlgo::Sha256),
+            enc_key: "c286696d887c9aa0611bbb3e2025a45a3e1a1c4b58f1a6b2d6f2a1c8e3b5f7a9",
+            mac_key: "9b0a6d4e1f3c2b5a7d8e6f4c3b2a1d0e5f6a7b8c9d0e1f2a3b4c5d6e7f8a9b0c",
+            spi: 0xc0ffee01,
+            seq: 3,
+            payload: IPV6_HEX,
+            next_header: NextHeader::Ipv6,
+            wire:
+                "c0ffee01000000033dafba429d9eb430b422da802c9fac41de208effe8ad4ad397e4f5ebdf142d6d\
+                   cc5bace4b353f126c8c8b55c29074c380e9dd24c95f3112f1f2241d177606064ba6413b37f6f41d5\
+                   b8f4ef36ff2d62c363ff12a78926bcc0236b1f783790d3a6",
+        },
+        Fixture {
+            enc_algo: EncAlgo::Aes256Gcm,
+            hmac_algo: None,
+            enc_key: "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308cafebabe",
+            mac_key: "",
+            spi: 0x00004321,
+            seq: 0x17,
+            payload: IPV6_HEX,
+            next_header: NextHeader::Ipv6,
+            wire:
+                "00004321000000170000000000000017a2bbdfeebd2a68fe9bd2d48573b6a9fbe35f9b2bfb715ae7\
+                   1e00edba7d6427b3e7c6605a5fd3599f7e7b5accc71e184f77a1a8ee744706bdeaebb5a2f75348cc\
+                   ce3e70a0",
+        },
+    ];
+
+    /// 只有版本号的 IPv4 包, 足以让 `encapsulate` 识别内层协议
+    fn ipv4(body: &[u8]) -> Vec<u8> {
+        [&[0x45][..], body].concat()
+    }
 
     #[test]
     fn test_esp() {
-        let data_hex =
-        "4500002cdc4c40004001a5480ac121010ac182b90000103c474702cd6d6f6e69746f72000070616e20686120";
-        let orig_data = hex::decode(data_hex).unwrap();
+        let orig_data = hex::decode(DATA_HEX).unwrap();
 
         let esp = ESP::new(
             1u32,
             0x6f77893a,
-            hex::decode("510f909f4014dfec78b3bb8c7cbe86ac")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-            hex::decode("678c7e80dd68ee69e1279da28054186de9ec113c")
-                .unwrap()
+            EncAlgo::Aes128Cbc,
+            Some(HmacAlgo::Sha1),
+            &hex::decode("510f909f4014dfec78b3bb8c7cbe86ac").unwrap(),
+            &hex::decode("678c7e80dd68ee69e1279da28054186de9ec113c").unwrap(),
+        )
+        .unwrap();
+        let data_out = esp.encapsulate(&orig_data).unwrap();
+
+        let mut esppacket_in = ESPPacket::from_bytes(&esp, &data_out).unwrap();
+        assert_eq!(esppacket_in.spi, 0x6f77893a);
+        assert_eq!(esppacket_in.seq, 1);
+        assert_eq!(esppacket_in.iv, data_out[8..24]);
+        assert_eq!(esppacket_in.data.len(), 48);
+        assert_eq!(esppacket_in.icv, data_out[data_out.len() - 12..]);
+
+        // 48 字节密文 = 44 字节载荷 + 2 字节填充 1, 2 + 填充长度 2 + 下一个头部 4
+        let raw = cbc::decrypt(
+            &hex::decode("510f909f4014dfec78b3bb8c7cbe86ac").unwrap(),
+            &esppacket_in.data,
+            &esppacket_in.iv,
+        );
+        assert_eq!(&raw[..44], &orig_data[..]);
+        assert_eq!(&raw[44..], &[1, 2, 2, 4]);
+
+        match esppacket_in.decrypt(&esp) {
+            Ok(next_header) => {
+                assert_eq!(next_header, NextHeader::Ipv4);
+                assert_eq!(esppacket_in.data, orig_data);
+            }
+            Err(e) => panic!("Decrypt error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_esp_gcm() {
+        let orig_data = hex::decode(DATA_HEX).unwrap();
+
+        for (algo, key) in [
+            (
+                EncAlgo::Aes128Gcm,
+                "feffe9928665731c6d6a8f9467308308cafebabe",
+            ),
+            (
+                EncAlgo::Aes256Gcm,
+                "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308cafebabe",
+            ),
+        ] {
+            let key = hex::decode(key).unwrap();
+            let esp = ESP::new(10, 0x00004321, algo, None, &key, &[]).unwrap();
+            let wire = esp.encapsulate(&orig_data).unwrap();
+
+            // SPI | 序号 | 8 字节 IV | 密文 (4 字节对齐) | 16 字节 ICV
+            assert_eq!(&wire[..8], &hex::decode("000043210000000a").unwrap()[..]);
+            assert_eq!((wire.len() - 8 - 8 - 16) % 4, 0);
+
+            // nonce 由 salt 和显式 IV 组成, ESP 头作为附加认证数据
+            let iv = &wire[8..16];
+            let nonce: [u8; 12] = [&hex::decode("cafebabe").unwrap()[..], iv]
+                .concat()
                 .try_into()
-                .unwrap(),
+                .unwrap();
+            let plain =
+                gcm::decrypt(&key[..key.len() - 4], &nonce, &wire[..8], &wire[16..]).unwrap();
+            assert_eq!(&plain[..orig_data.len()], &orig_data[..]);
+            assert_eq!(plain[plain.len() - 1], 4);
+
+            let mut received = ESPPacket::from_bytes(&esp, &wire).unwrap();
+            received.decrypt(&esp).unwrap();
+            assert_eq!(received.data, orig_data);
+
+            // 篡改 ESP 头后认证失败
+            let mut tampered = wire.clone();
+            tampered[7] ^= 1;
+            let mut received = ESPPacket::from_bytes(&esp, &tampered).unwrap();
+            assert!(received.decrypt(&esp).is_err());
+        }
+
+        assert!(ESP::new(1, 1, EncAlgo::Aes256Gcm, None, &[0u8; 32], &[]).is_err());
+        assert_eq!(
+            "aes-256-gcm".parse::<EncAlgo>().unwrap(),
+            EncAlgo::Aes256Gcm
         );
-        let mut esppacket = ESPPacket::new(&esp, orig_data.clone().try_into().unwrap());
-        if let Err(e) = esppacket.encrypt(&esp) {
-            panic!("Encrypt error: {}", e);
+    }
+
+    #[test]
+    fn test_esp_cbc_sha2() {
+        let orig_data = hex::decode(DATA_HEX).unwrap();
+
+        for hmac in [HmacAlgo::Sha256, HmacAlgo::Sha384, HmacAlgo::Sha512] {
+            let mac_key = vec![0x42u8; hmac.key_len()];
+            let esp = ESP::new(
+                7,
+                0x6f77893a,
+                EncAlgo::Aes256Cbc,
+                Some(hmac),
+                &[0x24u8; 32],
+                &mac_key,
+            )
+            .unwrap();
+            let wire = esp.encapsulate(&orig_data).unwrap();
+            assert_eq!((wire.len() - 8 - 16 - hmac.icv_len()) % 16, 0);
+
+            let mut received = ESPPacket::from_bytes(&esp, &wire).unwrap();
+            received.decrypt(&esp).unwrap();
+            assert_eq!(received.data, orig_data);
+
+            // ICV 覆盖 ESP 头
+            let mut tampered = wire.clone();
+            tampered[7] ^= 1;
+            let mut received = ESPPacket::from_bytes(&esp, &tampered).unwrap();
+            assert!(received.decrypt(&esp).is_err());
         }
-        let data_out = esppacket.to_bytes();
+    }
 
-        let mut esppacket_in = ESPPacket::from_bytes(&data_out).unwrap();
-        assert_eq!(esppacket_in.spi, esppacket.spi);
-        assert_eq!(esppacket_in.seq, esppacket.seq);
-        assert_eq!(esppacket_in.iv, esppacket.iv);
-        assert_eq!(esppacket_in.data, esppacket.data);
-        assert_eq!(esppacket_in.hmac, esppacket.hmac);
+    #[test]
+    fn test_encapsulate_sequence_and_iv() {
+        let esp = ESP::new(
+            1,
+            0x1234,
+            EncAlgo::Aes128Cbc,
+            Some(HmacAlgo::Sha1),
+            &[1u8; 16],
+            &[2u8; 20],
+        )
+        .unwrap();
+        let first = esp.encapsulate(&ipv4(b"ping")).unwrap();
+        let second = esp.encapsulate(&ipv4(b"ping")).unwrap();
+        assert_eq!(&first[4..8], &1u32.to_be_bytes());
+        assert_eq!(&second[4..8], &2u32.to_be_bytes());
+        assert_ne!(first[8..24], second[8..24]);
 
-        match esppacket_in.decrypt(&esp) {
-            Ok(_) => assert_eq!(esppacket_in.data, orig_data),
-            Err(e) => panic!("Decrypt error: {}", e),
+        let gcm = ESP::new(1, 0x1234, EncAlgo::Aes128Gcm, None, &[3u8; 20], &[]).unwrap();
+        let first = gcm.encapsulate(&ipv4(b"ping")).unwrap();
+        let second = gcm.encapsulate(&ipv4(b"ping")).unwrap();
+        assert_ne!(first[8..16], second[8..16]);
+        assert_ne!(first[16..], second[16..]);
+    }
+
+    #[test]
+    fn test_sequence_does_not_wrap() {
+        let esp = ESP::new(
+            REKEY_THRESHOLD,
+            0x1234,
+            EncAlgo::Aes128Gcm,
+            None,
+            &[3u8; 20],
+            &[],
+        )
+        .unwrap();
+        assert!(!esp.needs_rekey());
+        esp.encapsulate(&ipv4(b"ping")).unwrap();
+        assert!(esp.needs_rekey());
+
+        let esp = ESP::new(u32::MAX, 0x1234, EncAlgo::Aes128Gcm, None, &[3u8; 20], &[]).unwrap();
+        let last = esp.encapsulate(&ipv4(b"ping")).unwrap();
+        assert_eq!(&last[4..8], &u32::MAX.to_be_bytes());
+        assert_eq!(
+            esp.encapsulate(&ipv4(b"ping")),
+            Err(EspError::SequenceExhausted)
+        );
+        assert_eq!(
+            esp.encapsulate(&ipv4(b"ping")),
+            Err(EspError::SequenceExhausted)
+        );
+    }
+
+    #[test]
+    fn test_decapsulate_rejects_replays() {
+        let key = [5u8; 20];
+        let outbound = ESP::new(1, 0x4321, EncAlgo::Aes128Gcm, None, &key, &[]).unwrap();
+        let inbound = ESP::new(1, 0x4321, EncAlgo::Aes128Gcm, None, &key, &[])
+            .unwrap()
+            .with_replay_window(128)
+            .unwrap();
+
+        let packets: Vec<Vec<u8>> = (0..200)
+            .map(|i| outbound.encapsulate(&ipv4(&[i as u8; 20])).unwrap())
+            .collect();
+        assert_eq!(inbound.decapsulate(&packets[1]).unwrap(), ipv4(&[1u8; 20]));
+        assert_eq!(inbound.decapsulate(&packets[0]).unwrap(), ipv4(&[0u8; 20]));
+        assert_eq!(inbound.decapsulate(&packets[1]), Err(EspError::Replayed(2)));
+        inbound.decapsulate(&packets[199]).unwrap();
+        assert_eq!(inbound.decapsulate(&packets[2]), Err(EspError::TooOld(3)));
+        inbound.decapsulate(&packets[150]).unwrap();
+
+        // 伪造的包不能推动窗口
+        let mut forged = outbound.encapsulate(&ipv4(b"forged")).unwrap();
+        forged[4..8].copy_from_slice(&5000u32.to_be_bytes());
+        assert_eq!(inbound.decapsulate(&forged), Err(EspError::Authentication));
+        inbound.decapsulate(&packets[100]).unwrap();
+
+        assert_eq!(
+            inbound.replay_stats(),
+            ReplayStats {
+                replayed: 1,
+                too_old: 1,
+                out_of_order: 3,
+            }
+        );
+        assert!(ESP::new(1, 1, EncAlgo::Aes128Gcm, None, &key, &[])
+            .unwrap()
+            .with_replay_window(8)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_packets() {
+        let esp = ESP::new(
+            1,
+            0x6f77893a,
+            EncAlgo::Aes128Cbc,
+            Some(HmacAlgo::Sha1),
+            &[1u8; 16],
+            &[2u8; 20],
+        )
+        .unwrap();
+        let wire = esp.encapsulate(&hex::decode(DATA_HEX).unwrap()).unwrap();
+
+        // 任意长度的截断都不能 panic
+        for len in 0..wire.len() {
+            assert!(esp.decapsulate(&wire[..len]).is_err());
+        }
+        assert_eq!(
+            ESPPacket::from_bytes(&esp, &wire[..20]).unwrap_err(),
+            EspError::TooShort { len: 20, min: 52 }
+        );
+        assert_eq!(
+            ESPPacket::from_bytes(&esp, &wire[..wire.len() - 4]).unwrap_err(),
+            EspError::Misaligned {
+                len: 44,
+                block_size: 16
+            }
+        );
+
+        let mut other_spi = wire.clone();
+        other_spi[..4].copy_from_slice(&0xdeadbeefu32.to_be_bytes());
+        assert_eq!(
+            esp.decapsulate(&other_spi),
+            Err(EspError::UnknownSpi(0xdeadbeef))
+        );
+    }
+
+    #[test]
+    fn test_trailer_validation() {
+        let pop = read_trailer;
+        assert_eq!(pop(&[0xaa, 0xbb, 0, 4]), Ok((2, NextHeader::Ipv4)));
+        assert_eq!(pop(&[0xaa, 1, 2, 3, 3, 41]), Ok((1, NextHeader::Ipv6)));
+        assert_eq!(pop(&[1, 2, 3, 4]), Err(EspError::PadLength(3)));
+        assert_eq!(pop(&[0xaa, 1, 3, 2, 4]), Err(EspError::Padding));
+        assert_eq!(pop(&[4]), Err(EspError::PadLength(0)));
+        assert_eq!(pop(&[255, 4]), Err(EspError::PadLength(255)));
+        // RFC 4303 的填充包
+        assert_eq!(pop(&[0xaa, 0xbb, 0, 59]), Err(EspError::NextHeader(59)));
+    }
+
+    #[test]
+    fn test_trailer_round_trip() {
+        // 网关回复的探测包 (IPv4) 和一个 IPv6 ICMPv6 echo request
+        for (packet, next_header) in [
+            (hex::decode(DATA_HEX).unwrap(), NextHeader::Ipv4),
+            (hex::decode(IPV6_HEX).unwrap(), NextHeader::Ipv6),
+        ] {
+            assert_eq!(NextHeader::of_packet(&packet), Some(next_header));
+            for block_size in [4, 16] {
+                for len in 0..=packet.len() {
+                    let mut data = packet[..len].to_vec();
+                    data.resize(len + trailer_len(len, block_size), 0);
+                    write_trailer(&mut data[len..], next_header);
+                    assert_eq!(data.len() % block_size, 0);
+                    assert!(data.len() - len - 2 < block_size);
+                    assert_eq!(data[data.len() - 1], next_header.protocol());
+                    assert_eq!(read_trailer(&data), Ok((len, next_header)));
+                    assert_eq!(&data[..len], &packet[..len]);
+                }
+            }
+        }
+        assert_eq!(NextHeader::of_packet(&[]), None);
+        assert_eq!(NextHeader::of_packet(&[0x00, 0x01]), None);
+    }
+
+    #[test]
+    fn test_fixtures() {
+        // 解密后的 `载荷 | 填充 | 填充长度 | 下一个头部`
+        fn plaintext(esp: &ESP, wire: &[u8]) -> Vec<u8> {
+            let packet = ESPPacket::from_bytes(esp, wire).unwrap();
+            esp.suite
+                .open(&packet.header(), &packet.iv, &packet.data, &packet.icv)
+                .unwrap()
+        }
+
+        for fixture in FIXTURES {
+            let new = || {
+                ESP::new(
+                    fixture.seq,
+                    fixture.spi,
+                    fixture.enc_algo,
+                    fixture.hmac_algo,
+                    &hex::decode(fixture.enc_key).unwrap(),
+                    &hex::decode(fixture.mac_key).unwrap(),
+                )
+                .unwrap()
+            };
+            let wire = hex::decode(fixture.wire).unwrap();
+            let payload = hex::decode(fixture.payload).unwrap();
+
+            assert_eq!(new().decapsulate(&wire).unwrap(), payload);
+            let mut packet = ESPPacket::from_bytes(&new(), &wire).unwrap();
+            assert_eq!(packet.decrypt(&new()), Ok(fixture.next_header));
+            assert_eq!(packet.data, payload);
+
+            // 重新封装得到相同的尾部; GCM 的 IV 取自序号, 整个包都相同
+            let esp = new();
+            let encapsulated = esp.encapsulate(&payload).unwrap();
+            let (expected, actual) = (plaintext(&esp, &wire), plaintext(&esp, &encapsulated));
+            assert_eq!(actual.len(), expected.len());
+            assert_eq!(actual[payload.len()..], expected[payload.len()..]);
+            if fixture.enc_algo.is_aead() {
+                assert_eq!(encapsulated, wire);
+            }
+        }
+    }
+
+    #[test]
+    fn test_in_place_round_trip() {
+        let packet = hex::decode(DATA_HEX).unwrap();
+        for (algo, hmac, key_len) in [
+            (EncAlgo::Aes128Cbc, Some(HmacAlgo::Sha1), 16),
+            (EncAlgo::Aes256Cbc, Some(HmacAlgo::Sha256), 32),
+            (EncAlgo::Aes256Gcm, None, 36),
+        ] {
+            let mac_key = vec![0x42u8; hmac.map_or(0, |h| h.key_len())];
+            let new = || ESP::new(1, 0x6f77893a, algo, hmac, &vec![0x24u8; key_len], &mac_key);
+            let (outbound, inbound) = (new().unwrap(), new().unwrap());
+
+            let headroom = outbound.headroom();
+            let mut buf = vec![0u8; headroom + packet.len() + outbound.tailroom()];
+            for _ in 0..3 {
+                buf[headroom..headroom + packet.len()].copy_from_slice(&packet);
+                let len = outbound
+                    .encapsulate_in_place(&mut buf, packet.len())
+                    .unwrap();
+                // 原地的结果与分配内存的接口互通
+                let copy = buf[..len].to_vec();
+                assert_eq!(inbound.decapsulate(&copy).unwrap(), packet);
+                assert_eq!(
+                    inbound.decapsulate_in_place(&mut buf[..len]),
+                    Err(EspError::Replayed(u32::from_be_bytes(
+                        copy[4..8].try_into().unwrap()
+                    )))
+                );
+
+                let wire = outbound.encapsulate(&packet).unwrap();
+                let mut received = wire.clone();
+                let payload = inbound.decapsulate_in_place(&mut received).unwrap();
+                assert_eq!(payload.start, headroom);
+                assert_eq!(&received[payload], &packet[..]);
+            }
+
+            assert_eq!(
+                outbound.encapsulate_in_place(&mut buf[..headroom + packet.len()], packet.len()),
+                Err(EspError::NoRoom {
+                    len: headroom + packet.len(),
+                    needed: buf.len(),
+                })
+            );
         }
     }
 }
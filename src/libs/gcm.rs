@@ -0,0 +1,145 @@
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce, Tag};
+
+pub const TAG_LEN: usize = 16;
+
+/// 展开好密钥的 AES-GCM, 可以在多个包之间复用
+#[derive(Clone)]
+pub enum Cipher {
+    Aes128(Box<Aes128Gcm>),
+    Aes256(Box<Aes256Gcm>),
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 不打印密钥
+        match self {
+            Cipher::Aes128(_) => f.write_str("Cipher::Aes128"),
+            Cipher::Aes256(_) => f.write_str("Cipher::Aes256"),
+        }
+    }
+}
+
+impl Cipher {
+    /// 根据密钥长度选择 AES-128 或 AES-256
+    pub fn new(key: &[u8]) -> Result<Self, String> {
+        match key.len() {
+            16 => Ok(Cipher::Aes128(Box::new(
+                Aes128Gcm::new_from_slice(key).unwrap(),
+            ))),
+            32 => Ok(Cipher::Aes256(Box::new(
+                Aes256Gcm::new_from_slice(key).unwrap(),
+            ))),
+            n => Err(format!("invalid AES-GCM key length: {}", n)),
+        }
+    }
+
+    /// 原地加密 `data`, 认证标签写入 `tag`
+    pub fn encrypt_in_place(&self, nonce: &[u8; 12], aad: &[u8], data: &mut [u8], tag: &mut [u8]) {
+        let nonce = Nonce::from_slice(nonce);
+        let result = match self {
+            Cipher::Aes128(aead) => aead.encrypt_in_place_detached(nonce, aad, data),
+            Cipher::Aes256(aead) => aead.encrypt_in_place_detached(nonce, aad, data),
+        };
+        tag.copy_from_slice(
+            &result.expect("AES-GCM encryption cannot fail for ESP sized payloads"),
+        );
+    }
+
+    /// 原地校验并解密, 标签不匹配时返回 false, 此时 `data` 的内容不可用
+    pub fn decrypt_in_place(
+        &self,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> bool {
+        if tag.len() != TAG_LEN {
+            return false;
+        }
+        let nonce = Nonce::from_slice(nonce);
+        let tag = Tag::from_slice(tag);
+        match self {
+            Cipher::Aes128(aead) => aead.decrypt_in_place_detached(nonce, aad, data, tag),
+            Cipher::Aes256(aead) => aead.decrypt_in_place_detached(nonce, aad, data, tag),
+        }
+        .is_ok()
+    }
+}
+
+/// AES-GCM 加密, 根据密钥长度选择 AES-128 或 AES-256, 返回密文和 16 字节认证标签
+pub fn encrypt(key: &[u8], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = plaintext.to_vec();
+    sealed.resize(plaintext.len() + TAG_LEN, 0);
+    let (data, tag) = sealed.split_at_mut(plaintext.len());
+    Cipher::new(key)
+        .unwrap()
+        .encrypt_in_place(nonce, aad, data, tag);
+    sealed
+}
+
+/// AES-GCM 解密, `ciphertext` 末尾带 16 字节认证标签, 标签不匹配时返回 None
+pub fn decrypt(key: &[u8], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Cipher::new(key).ok()?;
+    let data_len = ciphertext.len().checked_sub(TAG_LEN)?;
+    let (data, tag) = ciphertext.split_at(data_len);
+    let mut data = data.to_vec();
+    cipher
+        .decrypt_in_place(nonce, aad, &mut data, tag)
+        .then_some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // McGrew & Viega, "The Galois/Counter Mode of Operation", Test Case 4 和 16.
+    // 12 字节 IV 正好是 RFC 4106 的 4 字节 salt 加 8 字节显式 IV.
+    const PLAINTEXT: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39";
+    const AAD: &str = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+    const NONCE: &str = "cafebabefacedbaddecaf888";
+
+    fn check(key: &str, ciphertext: &str, tag: &str) {
+        let key = hex::decode(key).unwrap();
+        let nonce: [u8; 12] = hex::decode(NONCE).unwrap().try_into().unwrap();
+        let aad = hex::decode(AAD).unwrap();
+        let plaintext = hex::decode(PLAINTEXT).unwrap();
+
+        let sealed = encrypt(&key, &nonce, &aad, &plaintext);
+        assert_eq!(hex::encode(&sealed), format!("{}{}", ciphertext, tag));
+        assert_eq!(decrypt(&key, &nonce, &aad, &sealed).unwrap(), plaintext);
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt(&key, &nonce, &aad, &tampered).is_none());
+        assert!(decrypt(&key, &nonce, &[], &sealed).is_none());
+        assert!(decrypt(&key, &nonce, &aad, &sealed[..15]).is_none());
+
+        let cipher = Cipher::new(&key).unwrap();
+        let mut data = plaintext.clone();
+        let mut icv = [0u8; TAG_LEN];
+        cipher.encrypt_in_place(&nonce, &aad, &mut data, &mut icv);
+        assert_eq!(hex::encode(&data), ciphertext);
+        assert_eq!(hex::encode(icv), tag);
+        assert!(cipher.decrypt_in_place(&nonce, &aad, &mut data, &icv));
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_aes_128_gcm() {
+        check(
+            "feffe9928665731c6d6a8f9467308308",
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091",
+            "5bc94fbc3221a5db94fae95ae7121a47",
+        );
+    }
+
+    #[test]
+    fn test_aes_256_gcm() {
+        check(
+            "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308",
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+            "76fc6ece0f4e1768cddf8853bb2d551b",
+        );
+    }
+}
@@ -1,16 +1,25 @@
 use pnet::packet::icmp::echo_request::MutableEchoRequestPacket;
 use pnet::packet::icmp::{self, IcmpPacket, IcmpTypes};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::{checksum, MutableIpv4Packet};
-use pnet::packet::{udp, Packet};
+use pnet::packet::ipv4::{checksum, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::Packet;
 use std::net::Ipv4Addr;
 
-use super::esp::{self, ESPPacket, ESP};
-use super::udp::UdpClient;
+use super::esp::{ESPPacket, ESP};
 
 const MAGIC_PING_PAYLOAD: &[u8; 16] = b"monitor\x00\x00pan ha ";
 
-pub fn send_probes(esp: &ESP) -> Result<ESPPacket, Box<dyn std::error::Error>> {
+/// 构造一个封装好的 ESP 探测包
+pub fn send_probes(
+    esp: &ESP,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(esp.encapsulate(&probe_packet(source, destination))?)
+}
+
+/// 构造未封装的探测包: 从隧道地址 ping 网关的 gw-address, 负载是固定的 magic
+pub fn probe_packet(source: Ipv4Addr, destination: Ipv4Addr) -> Vec<u8> {
     // 计算 ICMP 数据包总长度 (ICMP 头部 + 自定义负载)
     let icmp_packet_size = 8 + MAGIC_PING_PAYLOAD.len();
 
@@ -24,8 +33,8 @@ pub fn send_probes(esp: &ESP) -> Result<ESPPacket, Box<dyn std::error::Error>> {
     ipv4_packet.set_total_length((20 + icmp_packet_size) as u16); // IP 头部 + ICMP 数据包
     ipv4_packet.set_ttl(64);
     ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
-    ipv4_packet.set_source(Ipv4Addr::new(10, 193, 129, 116));
-    ipv4_packet.set_destination(Ipv4Addr::new(10, 193, 33, 1));
+    ipv4_packet.set_source(source);
+    ipv4_packet.set_destination(destination);
 
     // 创建一个缓冲区来存储 ICMP 数据包
     let mut icmp_buffer = vec![0u8; icmp_packet_size]; // ICMP 头部 + 自定义负载
@@ -50,94 +59,51 @@ pub fn send_probes(esp: &ESP) -> Result<ESPPacket, Box<dyn std::error::Error>> {
     let ipv4_checksum = checksum(&ipv4_packet.to_immutable());
     ipv4_packet.set_checksum(ipv4_checksum);
 
-    // 打印构建的 IP 数据包
-    let data = ipv4_packet.packet();
-    let mut esppacket = ESPPacket::new(&esp, data.try_into().unwrap());
-    esppacket.encrypt(&esp)?;
-    Ok(esppacket)
+    ip_buffer
 }
 
 pub fn catch_probes(pkt: &ESPPacket) -> Result<bool, Box<dyn std::error::Error>> {
-    let mut data = pkt.data.clone();
-
-    // 校验icmp数据包内容是否为MAGIC_PING_PAYLOAD
-    let icmp_packet = MutableEchoRequestPacket::new(&mut data[20..]).unwrap();
-    let icmp_payload = icmp_packet.payload();
-    println!("Received packet: {:?}", hex::encode(&icmp_payload));
-    if icmp_payload.len() != MAGIC_PING_PAYLOAD.len() || icmp_payload != MAGIC_PING_PAYLOAD {
-        return Ok(false);
-    }
+    Ok(is_probe_reply(&pkt.data))
+}
 
-    Ok(true)
+/// 解密后的内层包是否是网关对探测包的回复: ICMP echo reply, 负载是 magic
+pub fn is_probe_reply(packet: &[u8]) -> bool {
+    let Some(ip) = Ipv4Packet::new(packet) else {
+        return false;
+    };
+    if ip.get_version() != 4 || ip.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        return false;
+    }
+    let header_len = ip.get_header_length() as usize * 4;
+    // ICMP 回显的负载前 4 字节是 identifier 和 sequence number
+    match packet.get(header_len..).and_then(IcmpPacket::new) {
+        Some(icmp) => {
+            icmp.get_icmp_type() == IcmpTypes::EchoReply
+                && icmp.payload().get(4..) == Some(&MAGIC_PING_PAYLOAD[..])
+        }
+        None => false,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::UdpSocket;
-
-    #[tokio::test]
-    async fn test_send_probes() {
-        let esp_out = ESP::new(
-            1u32,
-            0x54C277B0,
-            hex::decode("ce30001139e8cde103b214d7af0509e4")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-            hex::decode("f53b81dae1db0d5754ef52edc1516be18ca749f5")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        );
-
-        let esp_in = ESP::new(
-            1u32,
-            0x28E7990F,
-            hex::decode("a76d929d37613210f60bd233f2806b32")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-            hex::decode("0734369faa973a05f44dd0bb19d4559f10ed41b8")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        );
-        // 创建一个UDP socket
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-
-        // 目标地址
-        let addr = "47.100.9.56:4501";
-        // 尝试连接到目标地址
-        socket.connect(addr).unwrap();
-
-        // 输出连接成功
-        println!("连接成功到: {}", addr);
-
-        let pkt2send = send_probes(&esp_out).unwrap();
-        let data2send = pkt2send.to_bytes();
-
-        // 发送数据到目标地址
-        socket.send(&data2send).unwrap();
-        println!("已发送数据: {:?}", hex::encode(&data2send));
-
-        // 接收数据缓冲区
-        let mut recv_buffer = [0; 2048];
-
-        // 接收数据
-        let (amt, src) = socket.recv_from(&mut recv_buffer).unwrap();
-        println!("从 {} 接收到 {} 字节数据", src, amt);
-
-        // 输出接收到的数据
-        println!("接收到的数据: {:?}", &recv_buffer[..amt]);
-
-        let received_data = &recv_buffer[..amt];
-
-        let mut pkt2catch = ESPPacket::from_bytes(received_data).unwrap();
-        pkt2catch.decrypt(&esp_in).unwrap();
-        println!("解密后的数据: {:?}", hex::encode(pkt2catch.to_bytes()));
-        let is_valid = catch_probes(&pkt2catch).unwrap();
-
-        assert_eq!(is_valid, true);
+
+    #[test]
+    fn test_probe_reply() {
+        let mut probe = probe_packet(Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(probe.len(), 44);
+        // 发出的 echo request 本身不算回复
+        assert!(!is_probe_reply(&probe));
+        probe[20] = 0;
+        assert!(is_probe_reply(&probe));
+
+        assert!(!is_probe_reply(&probe[..30]));
+        assert!(!is_probe_reply(&[]));
+        probe[43] ^= 1;
+        assert!(!is_probe_reply(&probe));
+        probe[43] ^= 1;
+        probe[9] = 17;
+        assert!(!is_probe_reply(&probe));
     }
 }
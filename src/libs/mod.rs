@@ -1,5 +1,9 @@
 pub mod cbc;
-pub mod hmacsha1;
+pub mod cert;
 pub mod esp;
+pub mod gcm;
 pub mod gpst;
-pub mod udp;
\ No newline at end of file
+pub mod replay;
+pub mod suite;
+pub mod tls;
+pub mod udp;
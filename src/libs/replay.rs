@@ -0,0 +1,185 @@
+/// 入站 ESP 的防重放窗口 (RFC 4303 3.4.3)
+///
+/// 位图按 `seq % size` 环形存放, 窗口前移时只清除新进入窗口的位,
+/// 不需要整体移位.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    size: u32,
+    /// 已接受的最大序号, 0 表示尚未收到任何包
+    top: u32,
+    bitmap: Vec<u64>,
+    stats: ReplayStats,
+}
+
+/// 防重放窗口拒绝或乱序接受的包数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// 序号在窗口内但已经收到过
+    pub replayed: u64,
+    /// 序号落在窗口左边界之外
+    pub too_old: u64,
+    /// 序号小于已收到的最大序号但仍被接受
+    pub out_of_order: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    Replayed(u32),
+    TooOld(u32),
+}
+
+pub const MIN_WINDOW: u32 = 64;
+pub const MAX_WINDOW: u32 = 1024;
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        ReplayWindow::new(MIN_WINDOW).unwrap()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new(size: u32) -> Result<Self, String> {
+        if !(MIN_WINDOW..=MAX_WINDOW).contains(&size) {
+            return Err(format!(
+                "replay window must be between {} and {} packets, got {}",
+                MIN_WINDOW, MAX_WINDOW, size
+            ));
+        }
+        Ok(ReplayWindow {
+            size,
+            top: 0,
+            bitmap: vec![0; size.div_ceil(64) as usize],
+            stats: ReplayStats::default(),
+        })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
+    /// 在校验 ICV 之前快速丢弃重放的包, 不修改窗口
+    pub fn check(&mut self, seq: u32) -> Result<(), ReplayError> {
+        let result = self.classify(seq);
+        match result {
+            Err(ReplayError::Replayed(_)) => self.stats.replayed += 1,
+            Err(ReplayError::TooOld(_)) => self.stats.too_old += 1,
+            Ok(()) => {}
+        }
+        result
+    }
+
+    /// ICV 校验通过后记录序号; 并发收包时再检查一次, 保证同一序号只被接受一次
+    pub fn accept(&mut self, seq: u32) -> Result<(), ReplayError> {
+        self.check(seq)?;
+        if seq > self.top {
+            let advance = seq - self.top;
+            if advance >= self.size {
+                self.bitmap.fill(0);
+            } else {
+                for s in self.top + 1..seq {
+                    self.set(s, false);
+                }
+            }
+            self.top = seq;
+        } else {
+            self.stats.out_of_order += 1;
+        }
+        self.set(seq, true);
+        Ok(())
+    }
+
+    fn classify(&self, seq: u32) -> Result<(), ReplayError> {
+        // 序号从 1 开始, 0 不会出现在合法的包中
+        if seq == 0 {
+            return Err(ReplayError::TooOld(seq));
+        }
+        if seq > self.top {
+            return Ok(());
+        }
+        if self.top - seq >= self.size {
+            return Err(ReplayError::TooOld(seq));
+        }
+        match self.get(seq) {
+            true => Err(ReplayError::Replayed(seq)),
+            false => Ok(()),
+        }
+    }
+
+    fn position(&self, seq: u32) -> (usize, u64) {
+        let bit = seq % self.size;
+        ((bit / 64) as usize, 1u64 << (bit % 64))
+    }
+
+    fn get(&self, seq: u32) -> bool {
+        let (word, mask) = self.position(seq);
+        self.bitmap[word] & mask != 0
+    }
+
+    fn set(&mut self, seq: u32, value: bool) {
+        let (word, mask) = self.position(seq);
+        match value {
+            true => self.bitmap[word] |= mask,
+            false => self.bitmap[word] &= !mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new(64).unwrap();
+        for seq in [1, 2, 3, 5] {
+            window.accept(seq).unwrap();
+        }
+        // 乱序但在窗口内
+        window.accept(4).unwrap();
+        assert_eq!(window.check(4), Err(ReplayError::Replayed(4)));
+        assert_eq!(window.accept(5), Err(ReplayError::Replayed(5)));
+
+        window.accept(100).unwrap();
+        assert_eq!(window.check(36), Err(ReplayError::TooOld(36)));
+        window.accept(37).unwrap();
+        assert_eq!(window.check(37), Err(ReplayError::Replayed(37)));
+        assert_eq!(window.check(0), Err(ReplayError::TooOld(0)));
+
+        assert_eq!(
+            window.stats(),
+            ReplayStats {
+                replayed: 3,
+                too_old: 2,
+                out_of_order: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_window_advance_clears_stale_bits() {
+        let mut window = ReplayWindow::new(100).unwrap();
+        window.accept(1).unwrap();
+        window.accept(50).unwrap();
+        // 前移到 101 后 1 滑出窗口, 1 的位被 101 复用, 51..100 的旧位被清除
+        window.accept(101).unwrap();
+        window.accept(51).unwrap();
+        assert_eq!(window.check(1), Err(ReplayError::TooOld(1)));
+        assert_eq!(window.check(50), Err(ReplayError::Replayed(50)));
+        window.accept(1000).unwrap();
+        window.accept(999).unwrap();
+        assert_eq!(window.check(901), Ok(()));
+        assert_eq!(window.check(900), Err(ReplayError::TooOld(900)));
+        assert_eq!(window.check(u32::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_window_size_limits() {
+        assert!(ReplayWindow::new(32).is_err());
+        assert!(ReplayWindow::new(2048).is_err());
+        assert_eq!(ReplayWindow::new(1024).unwrap().size(), 1024);
+    }
+}
@@ -0,0 +1,288 @@
+use super::cbc;
+use super::esp::{EncAlgo, HmacAlgo};
+use super::gcm;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use std::fmt;
+
+/// ESP 载荷的加密和完整性保护算法
+///
+/// `ESP` 负责 ESP 头, IV 和填充, 具体的加解密交给 suite.
+/// 新的算法组合只需实现这个 trait 并在 `new_suite` 中注册.
+/// suite 在创建时展开密钥, 之后每个包不再重复计算.
+pub trait CipherSuite: fmt::Debug + Send + Sync {
+    /// 随包发送的 IV 长度
+    fn iv_len(&self) -> usize;
+    /// 明文加上填充和尾部后需要对齐的长度
+    fn block_size(&self) -> usize;
+    /// 包尾完整性校验值的长度
+    fn icv_len(&self) -> usize;
+    /// 原地加密已经填充好的明文, 把 ICV 写入长度为 `icv_len` 的 `icv`, `header` 是 SPI 和序号
+    fn seal_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &mut [u8]);
+    /// 校验 ICV 并原地解密, 认证失败时返回 false, 此时 `data` 的内容不可用
+    fn open_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &[u8]) -> bool;
+
+    /// 加密已经填充好的明文, 返回密文和 ICV
+    fn seal(&self, header: &[u8; 8], iv: &[u8], plaintext: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = plaintext.to_vec();
+        let mut icv = vec![0u8; self.icv_len()];
+        self.seal_in_place(header, iv, &mut data, &mut icv);
+        (data, icv)
+    }
+
+    /// 校验 ICV 并解密, 认证失败时返回 None
+    fn open(&self, header: &[u8; 8], iv: &[u8], ciphertext: &[u8], icv: &[u8]) -> Option<Vec<u8>> {
+        let mut data = ciphertext.to_vec();
+        self.open_in_place(header, iv, &mut data, icv)
+            .then_some(data)
+    }
+}
+
+/// 根据网关协商的算法和密钥创建 suite, `mac_key` 在 AEAD 算法下被忽略
+pub fn new_suite(
+    enc_algo: EncAlgo,
+    hmac_algo: Option<HmacAlgo>,
+    enc_key: &[u8],
+    mac_key: &[u8],
+) -> Result<Box<dyn CipherSuite>, String> {
+    if enc_key.len() != enc_algo.key_len() {
+        return Err(format!(
+            "{} needs a {}-bit key, got {} bits",
+            enc_algo,
+            enc_algo.key_len() * 8,
+            enc_key.len() * 8
+        ));
+    }
+    match enc_algo {
+        EncAlgo::Aes128Cbc | EncAlgo::Aes256Cbc => {
+            let hmac = hmac_algo.ok_or_else(|| format!("{} needs an hmac algorithm", enc_algo))?;
+            if mac_key.len() != hmac.key_len() {
+                return Err(format!(
+                    "hmac-{} needs a {}-bit key, got {} bits",
+                    hmac,
+                    hmac.key_len() * 8,
+                    mac_key.len() * 8
+                ));
+            }
+            Ok(Box::new(CbcHmac::new(enc_key, hmac, mac_key)?))
+        }
+        EncAlgo::Aes128Gcm | EncAlgo::Aes256Gcm => {
+            // RFC 4106: 密钥材料的最后 4 字节作为 nonce 的 salt
+            let (key, salt) = enc_key.split_at(enc_key.len() - GCM_SALT_LEN);
+            Ok(Box::new(AesGcm {
+                cipher: gcm::Cipher::new(key)?,
+                salt: salt.try_into().unwrap(),
+            }))
+        }
+    }
+}
+
+pub(crate) const GCM_SALT_LEN: usize = 4;
+
+/// 已经设置好密钥的 HMAC 状态, 每个包从它克隆后再计算, 不必重新处理密钥
+#[derive(Clone)]
+enum KeyedHmac {
+    Sha1(Hmac<Sha1>),
+    Sha256(Hmac<Sha256>),
+    Sha384(Hmac<Sha384>),
+    Sha512(Hmac<Sha512>),
+}
+
+impl fmt::Debug for KeyedHmac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 不打印密钥
+        f.write_str("KeyedHmac")
+    }
+}
+
+/// AES-CBC 加密, HMAC 覆盖 ESP 头, IV 和密文 (RFC 4303 2.8)
+#[derive(Debug)]
+struct CbcHmac {
+    cipher: cbc::Cipher,
+    hmac: HmacAlgo,
+    mac: KeyedHmac,
+}
+
+impl CbcHmac {
+    fn new(enc_key: &[u8], hmac: HmacAlgo, mac_key: &[u8]) -> Result<Self, String> {
+        let mac = match hmac {
+            HmacAlgo::Sha1 => KeyedHmac::Sha1(keyed(mac_key)),
+            HmacAlgo::Sha256 => KeyedHmac::Sha256(keyed(mac_key)),
+            HmacAlgo::Sha384 => KeyedHmac::Sha384(keyed(mac_key)),
+            HmacAlgo::Sha512 => KeyedHmac::Sha512(keyed(mac_key)),
+        };
+        Ok(CbcHmac {
+            cipher: cbc::Cipher::new(enc_key)?,
+            hmac,
+            mac,
+        })
+    }
+
+    /// 计算截断后的 ICV, 写入 `icv`
+    fn icv(&self, header: &[u8], iv: &[u8], ciphertext: &[u8], icv: &mut [u8]) {
+        let parts = [header, iv, ciphertext];
+        match &self.mac {
+            KeyedHmac::Sha1(mac) => hmac(mac, &parts, icv),
+            KeyedHmac::Sha256(mac) => hmac(mac, &parts, icv),
+            KeyedHmac::Sha384(mac) => hmac(mac, &parts, icv),
+            KeyedHmac::Sha512(mac) => hmac(mac, &parts, icv),
+        }
+    }
+}
+
+impl CipherSuite for CbcHmac {
+    fn iv_len(&self) -> usize {
+        16
+    }
+
+    fn block_size(&self) -> usize {
+        16
+    }
+
+    fn icv_len(&self) -> usize {
+        self.hmac.icv_len()
+    }
+
+    fn seal_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &mut [u8]) {
+        self.cipher.encrypt_in_place(iv, data);
+        self.icv(header, iv, data, icv);
+    }
+
+    fn open_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &[u8]) -> bool {
+        if icv.len() != self.icv_len() || data.is_empty() || !data.len().is_multiple_of(16) {
+            return false;
+        }
+        let mut expected = [0u8; 32];
+        let expected = &mut expected[..icv.len()];
+        self.icv(header, iv, data, expected);
+        // 逐字节比较全部内容, 不因第一个不同的字节提前返回
+        let diff = expected
+            .iter()
+            .zip(icv)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return false;
+        }
+        self.cipher.decrypt_in_place(iv, data);
+        true
+    }
+}
+
+fn keyed<M: Mac + hmac::digest::KeyInit>(key: &[u8]) -> M {
+    <M as hmac::digest::KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any size")
+}
+
+fn hmac<M: Mac + Clone>(mac: &M, parts: &[&[u8]], icv: &mut [u8]) {
+    let mut mac = mac.clone();
+    for part in parts {
+        mac.update(part);
+    }
+    let digest = mac.finalize().into_bytes();
+    icv.copy_from_slice(&digest[..icv.len()]);
+}
+
+/// AES-GCM (RFC 4106), nonce 为 salt 加 8 字节显式 IV, ESP 头作为附加认证数据
+#[derive(Debug)]
+struct AesGcm {
+    cipher: gcm::Cipher,
+    salt: [u8; GCM_SALT_LEN],
+}
+
+impl AesGcm {
+    fn nonce(&self, iv: &[u8]) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..GCM_SALT_LEN].copy_from_slice(&self.salt);
+        nonce[GCM_SALT_LEN..].copy_from_slice(iv);
+        nonce
+    }
+}
+
+impl CipherSuite for AesGcm {
+    fn iv_len(&self) -> usize {
+        8
+    }
+
+    fn block_size(&self) -> usize {
+        4
+    }
+
+    fn icv_len(&self) -> usize {
+        gcm::TAG_LEN
+    }
+
+    fn seal_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &mut [u8]) {
+        self.cipher
+            .encrypt_in_place(&self.nonce(iv), header, data, icv);
+    }
+
+    fn open_in_place(&self, header: &[u8; 8], iv: &[u8], data: &mut [u8], icv: &[u8]) -> bool {
+        self.cipher
+            .decrypt_in_place(&self.nonce(iv), header, data, icv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha2_icv() {
+        // RFC 4231 Test Case 2, key "Jefe", data "what do ya want for nothing?"
+        let data = b"what do ya want for nothing?";
+        for (algo, expected) in [
+            (HmacAlgo::Sha256, "5bdcc146bf60754e6a042426089575c7"),
+            (
+                HmacAlgo::Sha384,
+                "af45d2e376484031617f78d2b58a6b1b9c7ef464f5a01b47",
+            ),
+            (
+                HmacAlgo::Sha512,
+                "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554",
+            ),
+        ] {
+            let suite = CbcHmac::new(&[0u8; 32], algo, b"Jefe").unwrap();
+            let mut icv = vec![0u8; algo.icv_len()];
+            suite.icv(&data[..8], &data[8..12], &data[12..], &mut icv);
+            assert_eq!(hex::encode(&icv), expected);
+        }
+    }
+
+    #[test]
+    fn test_new_suite_checks_keys() {
+        let suite = new_suite(
+            EncAlgo::Aes256Cbc,
+            Some(HmacAlgo::Sha512),
+            &[1u8; 32],
+            &[2u8; 64],
+        )
+        .unwrap();
+        assert_eq!(suite.icv_len(), 32);
+
+        let header = [0u8, 0, 0, 1, 0, 0, 0, 1];
+        let iv = [7u8; 16];
+        let (ciphertext, icv) = suite.seal(&header, &iv, &[0x55u8; 32]);
+        assert_eq!(ciphertext.len(), 32);
+        assert_eq!(
+            suite.open(&header, &iv, &ciphertext, &icv).unwrap(),
+            [0x55u8; 32]
+        );
+        assert!(suite.open(&header, &iv, &ciphertext, &icv[..16]).is_none());
+
+        assert!(new_suite(EncAlgo::Aes256Cbc, None, &[1u8; 32], &[]).is_err());
+        assert!(new_suite(
+            EncAlgo::Aes256Cbc,
+            Some(HmacAlgo::Sha256),
+            &[1u8; 32],
+            &[2u8; 20]
+        )
+        .is_err());
+        assert!(new_suite(
+            EncAlgo::Aes128Cbc,
+            Some(HmacAlgo::Sha1),
+            &[1u8; 32],
+            &[2u8; 20]
+        )
+        .is_err());
+    }
+}
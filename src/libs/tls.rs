@@ -0,0 +1,369 @@
+use super::cert::{ClientCertificate, PrivateKey};
+use base64::prelude::*;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{
+    CertificateDer, PrivateKeyDer, PrivatePkcs1KeyDer, PrivatePkcs8KeyDer, PrivateSec1KeyDer,
+    ServerName, UnixTime,
+};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use x509_cert::der::{Decode, Encode};
+
+/// 服务器证书指纹
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fingerprint {
+    /// 整个证书 DER 的 SHA-256, 写作 `sha256:<hex>`
+    Certificate([u8; 32]),
+    /// SubjectPublicKeyInfo 的 SHA-256, 写作 `pin-sha256:<base64>`
+    PublicKey([u8; 32]),
+}
+
+impl Fingerprint {
+    pub fn of_certificate(cert: &[u8]) -> Self {
+        Fingerprint::Certificate(Sha256::digest(cert).into())
+    }
+
+    pub fn of_public_key(cert: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let cert = x509_cert::Certificate::from_der(cert)?;
+        let spki = cert.tbs_certificate().subject_public_key_info().to_der()?;
+        Ok(Fingerprint::PublicKey(Sha256::digest(spki).into()))
+    }
+
+    fn matches(&self, cert: &[u8]) -> bool {
+        match self {
+            Fingerprint::Certificate(_) => Fingerprint::of_certificate(cert) == *self,
+            Fingerprint::PublicKey(_) => {
+                Fingerprint::of_public_key(cert).is_ok_and(|fp| fp == *self)
+            }
+        }
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digest: Vec<u8> = if let Some(b64) = s.strip_prefix("pin-sha256:") {
+            BASE64_STANDARD.decode(b64).map_err(|e| e.to_string())?
+        } else if let Some(hex) = s.strip_prefix("sha256:") {
+            hex::decode(hex.replace(':', "")).map_err(|e| e.to_string())?
+        } else {
+            return Err(format!(
+                "unknown fingerprint {:?}, expected pin-sha256:<base64> or sha256:<hex>",
+                s
+            ));
+        };
+        let digest: [u8; 32] = digest
+            .try_into()
+            .map_err(|_| "fingerprint is not a SHA-256 digest".to_string())?;
+        match s.starts_with("pin-sha256:") {
+            true => Ok(Fingerprint::PublicKey(digest)),
+            false => Ok(Fingerprint::Certificate(digest)),
+        }
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fingerprint::Certificate(d) => write!(f, "sha256:{}", hex::encode(d)),
+            Fingerprint::PublicKey(d) => write!(f, "pin-sha256:{}", BASE64_STANDARD.encode(d)),
+        }
+    }
+}
+
+/// 服务器证书的验证方式
+#[derive(Debug, Clone, Default)]
+pub enum TlsVerify {
+    /// 系统根证书
+    #[default]
+    System,
+    /// PEM 格式的 CA 证书文件
+    CaFile(PathBuf),
+    /// 门户 getconfig 下发的根证书 (PEM)
+    PortalRootCa(Vec<String>),
+    /// 只接受指定指纹的证书, 适用于自签名的网关
+    Pinned(Vec<Fingerprint>),
+    /// 首次连接时把公钥指纹记录到文件, 之后只接受相同的公钥
+    TrustOnFirstUse(PathBuf),
+    /// 不验证服务器证书, 仅用于测试环境
+    #[cfg(feature = "danger-insecure-tls")]
+    Insecure,
+}
+
+/// 构造门户, 网关以及 SSL 隧道共用的 rustls 配置
+pub fn client_config(
+    verify: &TlsVerify,
+    client_cert: Option<&ClientCertificate>,
+) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match verify {
+        TlsVerify::System => {
+            let mut roots = RootCertStore::empty();
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                log::warn!("failed to load system certificate: {}", e);
+            }
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            log::debug!(
+                "loaded {} system root certificates ({} ignored)",
+                added,
+                ignored
+            );
+            builder.with_root_certificates(roots)
+        }
+        TlsVerify::CaFile(path) => {
+            let pem = std::fs::read_to_string(path)?;
+            builder.with_root_certificates(root_store(&[pem])?)
+        }
+        TlsVerify::PortalRootCa(pems) => builder.with_root_certificates(root_store(pems)?),
+        TlsVerify::Pinned(pins) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                provider,
+                pins: Pins::Fixed(pins.clone()),
+            })),
+        TlsVerify::TrustOnFirstUse(path) => {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    provider,
+                    pins: Pins::KnownHosts(Mutex::new(path.clone())),
+                }))
+        }
+        #[cfg(feature = "danger-insecure-tls")]
+        TlsVerify::Insecure => {
+            log::warn!("server certificate verification is disabled");
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    provider,
+                    pins: Pins::Any,
+                }))
+        }
+    };
+
+    let config = match client_cert {
+        Some(cert) => {
+            let chain = cert
+                .chain()
+                .iter()
+                .map(|der| CertificateDer::from(der.clone()))
+                .collect();
+            let key = match cert.key() {
+                PrivateKey::Pkcs8(der) => {
+                    PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(der.clone()))
+                }
+                PrivateKey::Pkcs1(der) => {
+                    PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(der.clone()))
+                }
+                PrivateKey::Sec1(der) => PrivateKeyDer::Sec1(PrivateSec1KeyDer::from(der.clone())),
+            };
+            builder.with_client_auth_cert(chain, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn root_store(pems: &[String]) -> Result<RootCertStore, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    for pem in pems {
+        for cert in rustls_pemfile::certs(&mut pem.as_bytes()) {
+            roots.add(cert?)?;
+        }
+    }
+    if roots.is_empty() {
+        return Err("no CA certificates found".into());
+    }
+    Ok(roots)
+}
+
+#[derive(Debug)]
+enum Pins {
+    Fixed(Vec<Fingerprint>),
+    /// known_hosts 文件, 每行 `<host> <fingerprint>`
+    KnownHosts(Mutex<PathBuf>),
+    #[cfg(feature = "danger-insecure-tls")]
+    Any,
+}
+
+/// 按指纹而不是 CA 链验证服务器证书, 握手签名仍然照常校验
+#[derive(Debug)]
+struct PinnedVerifier {
+    provider: Arc<CryptoProvider>,
+    pins: Pins,
+}
+
+impl PinnedVerifier {
+    fn check(&self, end_entity: &[u8], server_name: &ServerName<'_>) -> Result<(), String> {
+        match &self.pins {
+            Pins::Fixed(pins) => match pins.iter().any(|pin| pin.matches(end_entity)) {
+                true => Ok(()),
+                false => Err(format!(
+                    "certificate {} does not match any pinned fingerprint",
+                    Fingerprint::of_certificate(end_entity)
+                )),
+            },
+            Pins::KnownHosts(path) => {
+                let path = path.lock().unwrap();
+                let host = server_name.to_str();
+                let fingerprint =
+                    Fingerprint::of_public_key(end_entity).map_err(|e| e.to_string())?;
+                let known = std::fs::read_to_string(&*path).unwrap_or_default();
+                let recorded: Vec<Fingerprint> = known
+                    .lines()
+                    .filter_map(|line| line.split_once(' '))
+                    .filter(|(h, _)| *h == host)
+                    .filter_map(|(_, fp)| fp.trim().parse().ok())
+                    .collect();
+                if recorded.is_empty() {
+                    log::warn!("trusting {} on first use: {}", host, fingerprint);
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+                    }
+                    let mut file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&*path)
+                        .map_err(|e| e.to_string())?;
+                    writeln!(file, "{} {}", host, fingerprint).map_err(|e| e.to_string())?;
+                    return Ok(());
+                }
+                match recorded.contains(&fingerprint) {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "public key of {} changed to {}, remove its entry from {} if this is expected",
+                        host,
+                        fingerprint,
+                        path.display()
+                    )),
+                }
+            }
+            #[cfg(feature = "danger-insecure-tls")]
+            Pins::Any => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity, server_name)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(rustls::Error::General)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::cert::tests::CERT_PEM;
+
+    fn cert_der() -> Vec<u8> {
+        rustls_pemfile::certs(&mut CERT_PEM.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_fingerprint_format() {
+        let der = cert_der();
+        let cert_fp = Fingerprint::of_certificate(&der);
+        let key_fp = Fingerprint::of_public_key(&der).unwrap();
+
+        assert_eq!(cert_fp.to_string().parse::<Fingerprint>().unwrap(), cert_fp);
+        assert_eq!(key_fp.to_string().parse::<Fingerprint>().unwrap(), key_fp);
+        assert!(cert_fp.matches(&der));
+        assert!(key_fp.matches(&der));
+        assert!("md5:abcd".parse::<Fingerprint>().is_err());
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let path = std::env::temp_dir().join(format!("gpconnect-tofu-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let verifier = PinnedVerifier {
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            pins: Pins::KnownHosts(Mutex::new(path.clone())),
+        };
+        let der = cert_der();
+        let host = ServerName::try_from("vpn.example.com").unwrap();
+
+        assert!(verifier.check(&der, &host).is_ok());
+        assert!(verifier.check(&der, &host).is_ok());
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(recorded.lines().count(), 1);
+
+        // 同一主机换了证书必须拒绝
+        std::fs::write(
+            &path,
+            format!(
+                "vpn.example.com pin-sha256:{}\n",
+                BASE64_STANDARD.encode([0u8; 32])
+            ),
+        )
+        .unwrap();
+        assert!(verifier.check(&der, &host).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_client_config() {
+        let pin = Fingerprint::of_certificate(&cert_der());
+        assert!(client_config(&TlsVerify::Pinned(vec![pin]), None).is_ok());
+        assert!(client_config(&TlsVerify::PortalRootCa(vec![CERT_PEM.to_string()]), None).is_ok());
+        assert!(client_config(&TlsVerify::PortalRootCa(vec![]), None).is_err());
+    }
+}
@@ -40,6 +40,10 @@ impl UdpClient {
         })
     }
 
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
     pub async fn send(&self, data: Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.tx.send(data).await?;
         Ok(())
@@ -113,7 +117,7 @@ mod tests {
         assert_eq!(received_message, "Hello, client!");
 
         // 等待服务器完成
-        let _ = server_handle.await??;
+        server_handle.await??;
 
         Ok(())
     }
@@ -64,39 +64,7 @@
 //     println!("数据是否有效: {}", is_valid);
 // }
 
-use serde::{Deserialize, Serialize};
-use serde_xml_rs::from_str;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Response {
-    #[serde(rename = "gw-address")]
-    gw_address: String,
-    #[serde(rename = "ip-address")]
-    ip_address: String,
-    ipsec: Ipsec,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Ipsec {
-    #[serde(rename = "c2s-spi")]
-    c2s_spi: String,
-    #[serde(rename = "s2c-spi")]
-    s2c_spi: String,
-    #[serde(rename = "akey-s2c")]
-    akey_s2c: Key,
-    #[serde(rename = "ekey-s2c")]
-    ekey_s2c: Key,
-    #[serde(rename = "akey-c2s")]
-    akey_c2s: Key,
-    #[serde(rename = "ekey-c2s")]
-    ekey_c2s: Key,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Key {
-    bits: String,
-    val: String,
-}
+use gpconnect::gp::getconfig::parse_response;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let xml = r#"
@@ -170,28 +138,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	</response>
     "#;
 
-    let response: Response = from_str(xml)?;
+    let config = parse_response(xml)?;
+    let gateway = &config.gateway;
+    let ipsec = &gateway.ipsec;
 
-    println!("gw-address: {}", response.gw_address);
-    println!("ip-address: {}", response.ip_address);
-    println!("c2s-spi: {}", response.ipsec.c2s_spi);
-    println!("s2c-spi: {}", response.ipsec.s2c_spi);
-    println!(
-        "akey-s2c: {} bits, {}",
-        response.ipsec.akey_s2c.bits, response.ipsec.akey_s2c.val
-    );
-    println!(
-        "ekey-s2c: {} bits, {}",
-        response.ipsec.ekey_s2c.bits, response.ipsec.ekey_s2c.val
-    );
-    println!(
-        "akey-c2s: {} bits, {}",
-        response.ipsec.akey_c2s.bits, response.ipsec.akey_c2s.val
-    );
+    println!("gw-address: {}", gateway.gw_address);
+    println!("ip-address: {}", gateway.ip_address);
+    println!("c2s-spi: {:#010X}", ipsec.c2s_spi);
+    println!("s2c-spi: {:#010X}", ipsec.s2c_spi);
     println!(
-        "ekey-c2s: {} bits, {}",
-        response.ipsec.ekey_c2s.bits, response.ipsec.ekey_c2s.val
+        "enc-algo: {}, hmac-algo: {:?}",
+        ipsec.enc_algo, ipsec.hmac_algo
     );
+    println!("espin: {:?}", config.espin);
+    println!("espout: {:?}", config.espout);
 
     Ok(())
 }
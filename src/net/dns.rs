@@ -0,0 +1,670 @@
+use crate::gp::getconfig::GatewayConfig;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tokio::task::JoinHandle;
+
+use super::forwarder::DnsForwarder;
+
+/// 记录待恢复操作的状态文件, 进程崩溃后下次启动时据此恢复
+pub const DEFAULT_STATE_FILE: &str = "/run/gpconnect/dns.state";
+
+/// 没有 systemd-resolved 时分流用的本地转发器地址, 避开 127.0.0.53 等常见的本地 DNS
+pub const FORWARDER_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 153)), 53);
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// 网关下发的 DNS 服务器和搜索域
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+    pub servers: Vec<IpAddr>,
+    pub search: Vec<String>,
+    /// 只用于分流、不加入搜索列表的域名
+    pub routing_domains: Vec<String>,
+    /// 为 true 时只有属于 search 和 routing_domains 的查询发给网关的 DNS
+    pub split: bool,
+}
+
+impl DnsConfig {
+    pub fn from_gateway(config: &GatewayConfig) -> Self {
+        DnsConfig {
+            servers: config.dns.iter().chain(&config.dns_v6).copied().collect(),
+            search: config.dns_suffix.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// 启用分流, `domains` 是用户额外指定的内部域名
+    pub fn split(mut self, domains: &[String]) -> Self {
+        for domain in domains {
+            if !self.search.contains(domain) && !self.routing_domains.contains(domain) {
+                self.routing_domains.push(domain.clone());
+            }
+        }
+        self.split = true;
+        self
+    }
+
+    /// 分流时交给网关 DNS 的全部域名
+    pub fn split_domains(&self) -> Vec<String> {
+        self.search
+            .iter()
+            .chain(&self.routing_domains)
+            .cloned()
+            .collect()
+    }
+
+    /// resolv.conf 格式, resolvconf(8) 也接受同样的内容
+    fn to_resolv_conf(&self) -> String {
+        let mut text = String::new();
+        for server in &self.servers {
+            text.push_str(&format!("nameserver {}\n", server));
+        }
+        if !self.search.is_empty() {
+            text.push_str(&format!("search {}\n", self.search.join(" ")));
+        }
+        text
+    }
+}
+
+/// 修改系统 DNS 的方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsBackend {
+    /// 直接改写 resolv.conf, 原文件改名备份, 断开时改回
+    File(PathBuf),
+    /// 通过 resolvconf(8) 按网卡登记
+    Resolvconf,
+    /// 通过 D-Bus 设置 systemd-resolved 的 per-link DNS
+    #[cfg(feature = "resolved")]
+    Resolved,
+}
+
+impl DnsBackend {
+    /// 根据系统当前的 DNS 管理方式选择
+    pub fn detect() -> Self {
+        #[cfg(feature = "resolved")]
+        if Path::new("/run/systemd/resolve/stub-resolv.conf").exists()
+            && fs::read_link(RESOLV_CONF)
+                .map(|target| target.to_string_lossy().contains("systemd/resolve"))
+                .unwrap_or(false)
+        {
+            return DnsBackend::Resolved;
+        }
+        let has_resolvconf = [
+            "/usr/sbin/resolvconf",
+            "/sbin/resolvconf",
+            "/usr/bin/resolvconf",
+        ]
+        .iter()
+        .any(|path| Path::new(path).exists());
+        match has_resolvconf {
+            true => DnsBackend::Resolvconf,
+            false => DnsBackend::File(PathBuf::from(RESOLV_CONF)),
+        }
+    }
+}
+
+/// 恢复 DNS 所需的信息, 在修改之前写入状态文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsUndo {
+    File {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    Resolvconf {
+        record: String,
+    },
+    #[cfg(feature = "resolved")]
+    Resolved {
+        ifindex: u32,
+    },
+}
+
+impl fmt::Display for DnsUndo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsUndo::File { path, backup } => {
+                write!(f, "file\n{}\n{}", path.display(), backup.display())
+            }
+            DnsUndo::Resolvconf { record } => write!(f, "resolvconf\n{}", record),
+            #[cfg(feature = "resolved")]
+            DnsUndo::Resolved { ifindex } => write!(f, "systemd-resolved\n{}", ifindex),
+        }
+    }
+}
+
+impl std::str::FromStr for DnsUndo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines: Vec<&str> = s.lines().collect();
+        match lines[..] {
+            ["file", path, backup] => Ok(DnsUndo::File {
+                path: path.into(),
+                backup: backup.into(),
+            }),
+            ["resolvconf", record] => Ok(DnsUndo::Resolvconf {
+                record: record.to_string(),
+            }),
+            #[cfg(feature = "resolved")]
+            ["systemd-resolved", ifindex] => Ok(DnsUndo::Resolved {
+                ifindex: ifindex
+                    .parse()
+                    .map_err(|_| format!("invalid ifindex: {}", ifindex))?,
+            }),
+            _ => Err(format!("unrecognized DNS state: {:?}", s)),
+        }
+    }
+}
+
+impl DnsUndo {
+    async fn run(&self) -> io::Result<()> {
+        match self {
+            DnsUndo::File { path, backup } => {
+                // 备份不存在说明上次在改名之前就退出了, 原文件没有被改动
+                if backup.symlink_metadata().is_ok() {
+                    fs::rename(backup, path)?;
+                }
+                Ok(())
+            }
+            DnsUndo::Resolvconf { record } => resolvconf(&["-d", record, "-f"], None),
+            #[cfg(feature = "resolved")]
+            DnsUndo::Resolved { ifindex } => resolved::revert(*ifindex).await,
+        }
+    }
+}
+
+/// 在连接时应用 DNS 设置, 断开时恢复
+#[derive(Debug)]
+pub struct DnsManager {
+    backend: DnsBackend,
+    interface: String,
+    state_file: PathBuf,
+    undo: Option<DnsUndo>,
+    forwarder_addr: SocketAddr,
+    forwarder: Option<(SocketAddr, JoinHandle<io::Result<()>>)>,
+}
+
+impl DnsManager {
+    /// `interface` 是隧道网卡的名字
+    pub fn new(backend: DnsBackend, interface: &str, state_file: impl Into<PathBuf>) -> Self {
+        DnsManager {
+            backend,
+            interface: interface.to_string(),
+            state_file: state_file.into(),
+            undo: None,
+            forwarder_addr: FORWARDER_ADDR,
+            forwarder: None,
+        }
+    }
+
+    pub fn backend(&self) -> &DnsBackend {
+        &self.backend
+    }
+
+    /// 修改本地转发器的监听地址, 默认为 [`FORWARDER_ADDR`]
+    pub fn set_forwarder_addr(&mut self, addr: SocketAddr) {
+        self.forwarder_addr = addr;
+    }
+
+    /// 正在运行的本地转发器的地址
+    pub fn forwarder_addr(&self) -> Option<SocketAddr> {
+        self.forwarder.as_ref().map(|(addr, _)| *addr)
+    }
+
+    /// systemd-resolved 自己支持路由域, 其他方式分流时需要本地转发器
+    fn needs_forwarder(&self, config: &DnsConfig) -> bool {
+        match self.backend {
+            #[cfg(feature = "resolved")]
+            DnsBackend::Resolved => false,
+            _ => config.split,
+        }
+    }
+
+    /// 按修改前的 resolv.conf 找到原来的 DNS, 启动转发器
+    async fn start_forwarder(&mut self, config: &DnsConfig) -> io::Result<DnsConfig> {
+        let current = match &self.backend {
+            DnsBackend::File(path) => path.as_path(),
+            _ => Path::new(RESOLV_CONF),
+        };
+        let upstream: Vec<SocketAddr> = nameservers(&fs::read_to_string(current)?)
+            .into_iter()
+            .filter(|server| *server != self.forwarder_addr.ip())
+            .map(|server| SocketAddr::new(server, 53))
+            .collect();
+        if upstream.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no nameservers in {} to forward to", current.display()),
+            ));
+        }
+        let tunnel = config
+            .servers
+            .iter()
+            .map(|server| SocketAddr::new(*server, 53))
+            .collect();
+        let forwarder = DnsForwarder::bind(
+            self.forwarder_addr,
+            config.split_domains(),
+            tunnel,
+            upstream,
+        )
+        .await?;
+        let addr = forwarder.local_addr()?;
+        self.forwarder = Some((addr, tokio::spawn(forwarder.run())));
+        Ok(DnsConfig {
+            servers: vec![addr.ip()],
+            search: config.search.clone(),
+            ..Default::default()
+        })
+    }
+
+    pub async fn apply(&mut self, config: &DnsConfig) -> io::Result<()> {
+        if self.undo.is_some() || self.forwarder.is_some() {
+            self.restore().await?;
+        }
+        let local;
+        let config = match self.needs_forwarder(config) {
+            true => {
+                local = self.start_forwarder(config).await?;
+                &local
+            }
+            false => config,
+        };
+        let undo = match &self.backend {
+            DnsBackend::File(path) => DnsUndo::File {
+                path: path.clone(),
+                backup: backup_path(path),
+            },
+            DnsBackend::Resolvconf => DnsUndo::Resolvconf {
+                record: format!("{}.gpconnect", self.interface),
+            },
+            #[cfg(feature = "resolved")]
+            DnsBackend::Resolved => DnsUndo::Resolved {
+                ifindex: super::netlink::if_index(&self.interface)?,
+            },
+        };
+        // 先写状态文件, 中途崩溃也能恢复
+        if let Some(dir) = self.state_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.state_file, undo.to_string())?;
+        self.undo = Some(undo.clone());
+
+        let result = match (&self.backend, &undo) {
+            (DnsBackend::File(_), DnsUndo::File { path, backup }) => {
+                write_resolv_conf(path, backup, config)
+            }
+            (DnsBackend::Resolvconf, DnsUndo::Resolvconf { record }) => {
+                let text = config.to_resolv_conf();
+                resolvconf(&["-a", record], Some(&text))
+            }
+            #[cfg(feature = "resolved")]
+            (DnsBackend::Resolved, DnsUndo::Resolved { ifindex }) => {
+                resolved::apply(*ifindex, config).await
+            }
+            _ => unreachable!("undo record matches the backend"),
+        };
+        if let Err(e) = result {
+            if let Err(restore) = self.restore().await {
+                log::warn!("failed to restore DNS after error: {}", restore);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 恢复连接前的 DNS 设置, 没有应用过时什么也不做
+    pub async fn restore(&mut self) -> io::Result<()> {
+        // 转发器随进程退出, 不需要写入状态文件
+        if let Some((_, task)) = self.forwarder.take() {
+            task.abort();
+        }
+        if let Some(undo) = &self.undo {
+            undo.run().await?;
+            remove_state(&self.state_file)?;
+            self.undo = None;
+        }
+        Ok(())
+    }
+
+    /// 上次运行没有正常恢复 DNS 时按状态文件恢复, 返回是否做了恢复
+    pub async fn recover(state_file: &Path) -> io::Result<bool> {
+        let text = match fs::read_to_string(state_file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let undo: DnsUndo = text
+            .parse()
+            .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        log::info!("restoring DNS left over from a previous session");
+        undo.run().await?;
+        remove_state(state_file)?;
+        Ok(true)
+    }
+}
+
+impl Drop for DnsManager {
+    /// 没有调用 `restore` 就被丢弃时 (例如 panic) 尽量恢复.
+    /// 恢复在单独的线程和 runtime 中完成, 不依赖调用者是否处于 tokio runtime 中
+    fn drop(&mut self) {
+        if let Some((_, task)) = self.forwarder.take() {
+            task.abort();
+        }
+        let Some(undo) = self.undo.take() else {
+            return;
+        };
+        let state_file = self.state_file.clone();
+        let restored = std::thread::spawn(move || -> io::Result<()> {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(undo.run())?;
+            remove_state(&state_file)
+        })
+        .join();
+        match restored {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::warn!("failed to restore DNS: {}", e),
+            Err(_) => log::warn!("failed to restore DNS: restore thread panicked"),
+        }
+    }
+}
+
+fn remove_state(state_file: &Path) -> io::Result<()> {
+    match fs::remove_file(state_file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn nameservers(resolv_conf: &str) -> Vec<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .collect()
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".gpconnect");
+    path.with_file_name(name)
+}
+
+/// 原文件 (可能是符号链接) 改名为备份, 写入新文件, 保留原文件中的 options 行
+fn write_resolv_conf(path: &Path, backup: &Path, config: &DnsConfig) -> io::Result<()> {
+    let original = fs::read_to_string(path).unwrap_or_default();
+    let mut text = String::from("# Generated by gpconnect\n");
+    text.push_str(&config.to_resolv_conf());
+    for line in original.lines() {
+        if line.trim_start().starts_with("options") {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    if path.symlink_metadata().is_ok() {
+        fs::rename(path, backup)?;
+    }
+    fs::write(path, text)
+}
+
+fn resolvconf(args: &[&str], input: Option<&str>) -> io::Result<()> {
+    let mut child = Command::new("resolvconf")
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    drop(child.stdin.take());
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "resolvconf {} failed: {}",
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "resolved")]
+mod resolved {
+    use super::DnsConfig;
+    use std::io;
+    use std::net::IpAddr;
+    use zbus::Connection;
+
+    const DESTINATION: &str = "org.freedesktop.resolve1";
+    const PATH: &str = "/org/freedesktop/resolve1";
+    const INTERFACE: &str = "org.freedesktop.resolve1.Manager";
+
+    /// RevertLink 的网卡已经不存在时 systemd-resolved 返回的错误名
+    const NO_SUCH_LINK: [&str; 2] = ["org.freedesktop.resolve1.NoSuchLink", "System.Error.ENODEV"];
+
+    async fn call_method<B>(method: &str, body: &B) -> zbus::Result<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        let connection = Connection::system().await?;
+        connection
+            .call_method(Some(DESTINATION), PATH, Some(INTERFACE), method, body)
+            .await?;
+        Ok(())
+    }
+
+    async fn call<B>(method: &str, body: &B) -> io::Result<()>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        call_method(method, body)
+            .await
+            .map_err(|e| io::Error::other(format!("{} failed: {}", method, e)))
+    }
+
+    pub(super) fn is_no_such_link(e: &zbus::Error) -> bool {
+        matches!(e, zbus::Error::MethodError(name, _, _) if NO_SUCH_LINK.contains(&name.as_str()))
+    }
+
+    pub async fn apply(ifindex: u32, config: &DnsConfig) -> io::Result<()> {
+        let servers: Vec<(i32, Vec<u8>)> = config
+            .servers
+            .iter()
+            .map(|server| match server {
+                IpAddr::V4(v4) => (libc::AF_INET, v4.octets().to_vec()),
+                IpAddr::V6(v6) => (libc::AF_INET6, v6.octets().to_vec()),
+            })
+            .collect();
+        // 第二个字段为 false 表示既是搜索域也是路由域, true 表示只是路由域
+        let domains: Vec<(&str, bool)> = config
+            .search
+            .iter()
+            .map(|d| (d.as_str(), false))
+            .chain(config.routing_domains.iter().map(|d| (d.as_str(), true)))
+            .collect();
+        call("SetLinkDNS", &(ifindex as i32, servers)).await?;
+        call("SetLinkDomains", &(ifindex as i32, domains)).await?;
+        // 分流时其他查询仍走原来的 DNS
+        call("SetLinkDefaultRoute", &(ifindex as i32, !config.split)).await
+    }
+
+    pub async fn revert(ifindex: u32) -> io::Result<()> {
+        match call_method("RevertLink", &(ifindex as i32,)).await {
+            // 隧道网卡已经删除, systemd-resolved 会自动丢弃它的设置
+            Err(e) if is_no_such_link(&e) => Ok(()),
+            result => result.map_err(|e| io::Error::other(format!("RevertLink failed: {}", e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gpconnect-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config() -> DnsConfig {
+        DnsConfig {
+            servers: vec!["10.193.0.53".parse().unwrap(), "fd00::53".parse().unwrap()],
+            search: vec!["corp.example.com".to_string(), "example.com".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_backend_restores() {
+        let dir = temp_dir("dns");
+        let resolv = dir.join("resolv.conf");
+        let state = dir.join("run/dns.state");
+        let original = "nameserver 192.168.1.1\noptions edns0 trust-ad\n";
+        fs::write(&resolv, original).unwrap();
+
+        let mut manager = DnsManager::new(DnsBackend::File(resolv.clone()), "gpd0", &state);
+        manager.apply(&config()).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(&resolv).unwrap(),
+            "# Generated by gpconnect\n\
+             nameserver 10.193.0.53\n\
+             nameserver fd00::53\n\
+             search corp.example.com example.com\n\
+             options edns0 trust-ad\n"
+        );
+        assert!(state.exists());
+
+        manager.restore().await.unwrap();
+        assert_eq!(fs::read_to_string(&resolv).unwrap(), original);
+        assert!(!state.exists());
+        // 重复恢复不会出错
+        manager.restore().await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drop_restores() {
+        let dir = temp_dir("dns-drop");
+        let resolv = dir.join("resolv.conf");
+        let state = dir.join("dns.state");
+        let original = "nameserver 192.168.1.1\n";
+        fs::write(&resolv, original).unwrap();
+
+        let mut manager = DnsManager::new(DnsBackend::File(resolv.clone()), "gpd0", &state);
+        manager.set_forwarder_addr("127.0.0.1:0".parse().unwrap());
+        manager
+            .apply(&config().split(&["intranet".to_string()]))
+            .await
+            .unwrap();
+        let forwarder = manager.forwarder_addr().unwrap();
+        assert_ne!(fs::read_to_string(&resolv).unwrap(), original);
+
+        // 模拟 panic 展开: 没有调用 restore 就丢弃
+        drop(manager);
+        assert_eq!(fs::read_to_string(&resolv).unwrap(), original);
+        assert!(!state.exists());
+        // 转发器任务已经停止, 端口可以重新绑定
+        tokio::task::yield_now().await;
+        assert!(tokio::net::TcpListener::bind(forwarder).await.is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "resolved")]
+    #[test]
+    fn test_resolved_no_such_link() {
+        let reply = |name: &str| {
+            let message = zbus::message::Message::method_call("/", "RevertLink")
+                .unwrap()
+                .build(&())
+                .unwrap();
+            zbus::Error::MethodError(name.try_into().unwrap(), None, message)
+        };
+        assert!(resolved::is_no_such_link(&reply(
+            "org.freedesktop.resolve1.NoSuchLink"
+        )));
+        assert!(resolved::is_no_such_link(&reply("System.Error.ENODEV")));
+        assert!(!resolved::is_no_such_link(&reply(
+            "org.freedesktop.DBus.Error.AccessDenied"
+        )));
+        assert!(!resolved::is_no_such_link(&zbus::Error::Failure(
+            "No such device".into()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_recover_after_crash() {
+        let dir = temp_dir("dns-crash");
+        let target = dir.join("stub-resolv.conf");
+        let resolv = dir.join("resolv.conf");
+        let state = dir.join("dns.state");
+        fs::write(&target, "nameserver 127.0.0.53\n").unwrap();
+        std::os::unix::fs::symlink(&target, &resolv).unwrap();
+
+        let mut manager = DnsManager::new(DnsBackend::File(resolv.clone()), "gpd0", &state);
+        manager.apply(&config()).await.unwrap();
+        // 模拟崩溃: 不调用 restore
+        std::mem::forget(manager);
+        assert!(!resolv.is_symlink());
+
+        assert!(DnsManager::recover(&state).await.unwrap());
+        assert_eq!(fs::read_link(&resolv).unwrap(), target);
+        assert!(!DnsManager::recover(&state).await.unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_starts_forwarder() {
+        let dir = temp_dir("dns-split");
+        let resolv = dir.join("resolv.conf");
+        let state = dir.join("dns.state");
+        fs::write(&resolv, "nameserver 192.0.2.1\n").unwrap();
+
+        let mut manager = DnsManager::new(DnsBackend::File(resolv.clone()), "gpd0", &state);
+        manager.set_forwarder_addr("127.0.0.1:0".parse().unwrap());
+        let config = config().split(&["intranet".to_string(), "example.com".to_string()]);
+        assert_eq!(config.routing_domains, ["intranet"]);
+        manager.apply(&config).await.unwrap();
+        assert_eq!(manager.forwarder_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            fs::read_to_string(&resolv).unwrap(),
+            "# Generated by gpconnect\n\
+             nameserver 127.0.0.1\n\
+             search corp.example.com example.com\n"
+        );
+
+        manager.restore().await.unwrap();
+        assert!(manager.forwarder_addr().is_none());
+        assert_eq!(
+            fs::read_to_string(&resolv).unwrap(),
+            "nameserver 192.0.2.1\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let undo = DnsUndo::File {
+            path: "/etc/resolv.conf".into(),
+            backup: backup_path(Path::new("/etc/resolv.conf")),
+        };
+        assert_eq!(
+            undo.to_string(),
+            "file\n/etc/resolv.conf\n/etc/resolv.conf.gpconnect"
+        );
+        assert_eq!(undo.to_string().parse::<DnsUndo>().unwrap(), undo);
+
+        let undo = DnsUndo::Resolvconf {
+            record: "gpd0.gpconnect".to_string(),
+        };
+        assert_eq!(undo.to_string().parse::<DnsUndo>().unwrap(), undo);
+        assert!("file\n/etc/resolv.conf".parse::<DnsUndo>().is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+/// 每个上游服务器的等待时间, 超时后换下一个
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const HEADER_LEN: usize = 12;
+
+/// 本地 DNS 转发器: 查询名属于 VPN 域名的发给网关的 DNS, 其余发给原来的 DNS
+///
+/// 在同一个地址上监听 UDP 和 TCP, 客户端收到截断 (TC=1) 的回复后会改用 TCP 重试,
+/// TCP 上的查询同样通过 TCP 发给上游.
+#[derive(Debug)]
+pub struct DnsForwarder {
+    socket: UdpSocket,
+    listener: TcpListener,
+    domains: Vec<String>,
+    tunnel: Vec<SocketAddr>,
+    upstream: Vec<SocketAddr>,
+}
+
+impl DnsForwarder {
+    pub async fn bind(
+        listen: SocketAddr,
+        domains: Vec<String>,
+        tunnel: Vec<SocketAddr>,
+        upstream: Vec<SocketAddr>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(listen).await?;
+        // 端口为 0 时 TCP 使用 UDP 分配到的端口
+        let listener = TcpListener::bind(socket.local_addr()?).await?;
+        Ok(DnsForwarder {
+            socket,
+            listener,
+            domains: domains.iter().map(|d| normalize(d)).collect(),
+            tunnel,
+            upstream,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// 查询名对应的上游服务器
+    pub fn servers_for(&self, name: &str) -> &[SocketAddr] {
+        match in_domains(name, &self.domains) {
+            true => &self.tunnel,
+            false => &self.upstream,
+        }
+    }
+
+    /// 一直转发直到 socket 出错, 每个 UDP 查询和 TCP 连接在单独的任务里处理
+    pub async fn run(self) -> io::Result<()> {
+        let this = Arc::new(self);
+        tokio::try_join!(this.clone().serve_udp(), this.serve_tcp())?;
+        Ok(())
+    }
+
+    async fn serve_udp(self: Arc<Self>) -> io::Result<()> {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, client) = self.socket.recv_from(&mut buf).await?;
+            let query = buf[..len].to_vec();
+            let this = self.clone();
+            tokio::spawn(async move {
+                match this.resolve(&query, false).await {
+                    Some(reply) => {
+                        if let Err(e) = this.socket.send_to(&reply, client).await {
+                            log::debug!("failed to answer {}: {}", client, e);
+                        }
+                    }
+                    None => log::debug!("dropped DNS query from {}", client),
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>) -> io::Result<()> {
+        loop {
+            let (stream, client) = self.listener.accept().await?;
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.handle_tcp(stream).await {
+                    log::debug!("DNS connection from {}: {}", client, e);
+                }
+            });
+        }
+    }
+
+    /// 一个连接上可以有多个查询, 直到客户端关闭连接
+    async fn handle_tcp(&self, mut stream: TcpStream) -> io::Result<()> {
+        while let Some(query) = read_message(&mut stream).await? {
+            match self.resolve(&query, true).await {
+                Some(reply) => write_message(&mut stream, &reply).await?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, query: &[u8], tcp: bool) -> Option<Vec<u8>> {
+        let name = query_name(query)?;
+        for server in self.servers_for(&name) {
+            let reply = match tcp {
+                true => exchange_tcp(*server, query).await,
+                false => exchange(*server, query).await,
+            };
+            match reply {
+                Ok(reply) => return Some(reply),
+                Err(e) => log::debug!("{} via {}: {}", name, server, e),
+            }
+        }
+        None
+    }
+}
+
+async fn exchange(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let len = timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no reply"))??;
+        // ID 不一致的回复丢弃
+        if len >= HEADER_LEN && buf[..2] == query[..2] {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let reply = timeout(QUERY_TIMEOUT, async {
+        let mut stream = TcpStream::connect(server).await?;
+        write_message(&mut stream, query).await?;
+        read_message(&mut stream).await
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no reply"))??;
+    match reply {
+        Some(reply) if reply.len() >= HEADER_LEN && reply[..2] == query[..2] => Ok(reply),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad reply")),
+    }
+}
+
+/// 读取 TCP 上带 2 字节长度前缀的消息 (RFC 1035 4.2.2), 对方关闭连接时返回 None
+async fn read_message(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let len = match stream.read_u16().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut message = vec![0u8; len as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+async fn write_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message too long"))?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await
+}
+
+/// 解析第一个问题的查询名, 小写且不带结尾的点
+fn query_name(packet: &[u8]) -> Option<String> {
+    if packet.len() < HEADER_LEN || u16::from_be_bytes([packet[4], packet[5]]) == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // 问题部分不应出现压缩指针
+        if len > 63 {
+            return None;
+        }
+        let label = packet.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+    }
+    Some(labels.join("."))
+}
+
+fn normalize(domain: &str) -> String {
+    domain
+        .trim()
+        .trim_start_matches('~')
+        .trim_matches('.')
+        .to_ascii_lowercase()
+}
+
+/// 按标签边界做后缀匹配
+pub fn in_domains(name: &str, domains: &[String]) -> bool {
+    let name = normalize(name);
+    domains.iter().any(|domain| {
+        name == *domain
+            || (name.ends_with(domain.as_str())
+                && name.as_bytes()[name.len() - domain.len() - 1] == b'.')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+        packet
+    }
+
+    /// 把查询原样返回并在末尾加上标记
+    async fn fake_server(marker: u8) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let mut reply = buf[..len].to_vec();
+                reply[2] |= 0x80;
+                reply.push(marker);
+                socket.send_to(&reply, peer).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// TCP 版本的 `fake_server`
+    async fn fake_tcp_server(marker: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                while let Some(mut reply) = read_message(&mut stream).await.unwrap() {
+                    reply[2] |= 0x80;
+                    reply.push(marker);
+                    write_message(&mut stream, &reply).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_query_name() {
+        assert_eq!(
+            query_name(&query(1, "Intranet.Corp.example.com")).as_deref(),
+            Some("intranet.corp.example.com")
+        );
+        assert_eq!(query_name(&query(1, "a")[..14]), None);
+        let mut pointer = query(1, "a");
+        pointer[HEADER_LEN] = 0xc0;
+        assert_eq!(query_name(&pointer), None);
+    }
+
+    #[test]
+    fn test_in_domains() {
+        let domains = vec!["corp.example.com".to_string()];
+        assert!(in_domains("corp.example.com", &domains));
+        assert!(in_domains("wiki.corp.example.com.", &domains));
+        assert!(!in_domains("notcorp.example.com", &domains));
+        assert!(!in_domains("example.com", &domains));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_by_domain() {
+        let tunnel = fake_server(1).await;
+        let upstream = fake_server(2).await;
+        let forwarder = DnsForwarder::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            vec!["~Corp.Example.com.".to_string()],
+            vec![tunnel],
+            vec![upstream],
+        )
+        .await
+        .unwrap();
+        let addr = forwarder.local_addr().unwrap();
+        tokio::spawn(forwarder.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = [0u8; 512];
+        for (id, name, marker) in [(7, "wiki.corp.example.com", 1), (8, "www.example.org", 2)] {
+            let packet = query(id, name);
+            client.send_to(&packet, addr).await.unwrap();
+            let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..2], &packet[..2]);
+            assert_eq!(buf[len - 1], marker, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwards_over_tcp() {
+        let tunnel = fake_tcp_server(1).await;
+        let upstream = fake_tcp_server(2).await;
+        let forwarder = DnsForwarder::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            vec!["corp.example.com".to_string()],
+            vec![tunnel],
+            vec![upstream],
+        )
+        .await
+        .unwrap();
+        let addr = forwarder.local_addr().unwrap();
+        tokio::spawn(forwarder.run());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        for (id, name, marker) in [(7, "wiki.corp.example.com", 1), (8, "www.example.org", 2)] {
+            let packet = query(id, name);
+            write_message(&mut client, &packet).await.unwrap();
+            let reply = timeout(Duration::from_secs(5), read_message(&mut client))
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(&reply[..2], &packet[..2]);
+            assert_eq!(reply[reply.len() - 1], marker, "{}", name);
+        }
+    }
+}
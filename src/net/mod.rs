@@ -0,0 +1,8 @@
+#[cfg(target_os = "linux")]
+pub mod dns;
+pub mod forwarder;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+pub mod route;
+#[cfg(target_os = "linux")]
+pub mod script;
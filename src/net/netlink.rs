@@ -0,0 +1,326 @@
+use ipnet::IpNet;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// <linux/netlink.h> 和 <linux/rtnetlink.h>
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const RTMSG_LEN: usize = 12;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_STATIC: u8 = 4;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_NOWHERE: u8 = 255;
+const RTN_UNICAST: u8 = 1;
+
+/// 主路由表中的一条单播路由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub dest: IpNet,
+    pub gateway: Option<IpAddr>,
+    /// 出口网卡的 ifindex
+    pub oif: u32,
+    pub metric: Option<u32>,
+}
+
+/// NETLINK_ROUTE socket, 查询路由不需要权限, 修改路由需要 CAP_NET_ADMIN
+#[derive(Debug)]
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    /// 添加路由, 已经存在时返回 `ErrorKind::AlreadyExists`
+    pub fn add_route(&mut self, route: &RouteEntry) -> io::Result<()> {
+        let msg = self.route_message(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL, route);
+        self.transact(msg).map(|_| ())
+    }
+
+    pub fn del_route(&mut self, route: &RouteEntry) -> io::Result<()> {
+        let msg = self.route_message(RTM_DELROUTE, NLM_F_ACK, route);
+        self.transact(msg).map(|_| ())
+    }
+
+    /// 查询内核当前到达 `addr` 的路径, 相当于 `ip route get`
+    pub fn get_route(&mut self, addr: IpAddr) -> io::Result<RouteEntry> {
+        let dest = IpNet::from(addr);
+        let query = RouteEntry {
+            dest,
+            gateway: None,
+            oif: 0,
+            metric: None,
+        };
+        let msg = self.route_message(RTM_GETROUTE, 0, &query);
+        let reply = self.transact(msg)?;
+        let mut entry = parse_route(&reply).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed RTM_NEWROUTE reply")
+        })?;
+        entry.dest = dest;
+        // 查询结果中的 metric 不属于这条主机路由
+        entry.metric = None;
+        Ok(entry)
+    }
+
+    fn route_message(&mut self, kind: u16, flags: u16, route: &RouteEntry) -> Vec<u8> {
+        self.seq = self.seq.wrapping_add(1);
+        encode_route(kind, flags, self.seq, route)
+    }
+
+    /// 发送请求并等待应答, 返回第一个非 ACK 的消息
+    fn transact(&mut self, msg: Vec<u8>) -> io::Result<Vec<u8>> {
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), msg.as_ptr().cast(), msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut rest = &buf[..n as usize];
+            while let Some((kind, seq, body, next)) = split_message(rest) {
+                rest = next;
+                if seq != self.seq {
+                    continue;
+                }
+                match kind {
+                    NLMSG_ERROR => {
+                        let errno = body
+                            .get(..4)
+                            .map(|b| i32::from_ne_bytes(b.try_into().unwrap()))
+                            .unwrap_or(-libc::EPROTO);
+                        return match errno {
+                            0 => Ok(Vec::new()),
+                            errno => Err(io::Error::from_raw_os_error(-errno)),
+                        };
+                    }
+                    NLMSG_DONE => return Ok(Vec::new()),
+                    _ => return Ok(body.to_vec()),
+                }
+            }
+        }
+    }
+}
+
+pub fn if_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+pub fn if_name(index: u32) -> io::Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let ptr = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if ptr.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { std::ffi::CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+fn encode_route(kind: u16, flags: u16, seq: u32, route: &RouteEntry) -> Vec<u8> {
+    let family = match route.dest {
+        IpNet::V4(_) => libc::AF_INET,
+        IpNet::V6(_) => libc::AF_INET6,
+    };
+    // 与 iproute2 相同: 删除时 scope 为通配
+    let scope = match (kind, route.gateway) {
+        (RTM_DELROUTE, _) => RT_SCOPE_NOWHERE,
+        (RTM_NEWROUTE, None) => RT_SCOPE_LINK,
+        _ => RT_SCOPE_UNIVERSE,
+    };
+    let mut msg = vec![0u8; NLMSG_HDR_LEN];
+    msg.extend_from_slice(&[
+        family as u8,
+        route.dest.prefix_len(),
+        0,
+        0,
+        RT_TABLE_MAIN,
+        RTPROT_STATIC,
+        scope,
+        RTN_UNICAST,
+    ]);
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+
+    push_attr(&mut msg, RTA_DST, &ip_bytes(route.dest.addr()));
+    if let Some(gateway) = route.gateway {
+        push_attr(&mut msg, RTA_GATEWAY, &ip_bytes(gateway));
+    }
+    if route.oif != 0 {
+        push_attr(&mut msg, RTA_OIF, &route.oif.to_ne_bytes());
+    }
+    if let Some(metric) = route.metric {
+        push_attr(&mut msg, RTA_PRIORITY, &metric.to_ne_bytes());
+    }
+
+    let len = msg.len() as u32;
+    msg[0..4].copy_from_slice(&len.to_ne_bytes());
+    msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+    msg[6..8].copy_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+    msg[8..12].copy_from_slice(&seq.to_ne_bytes());
+    msg
+}
+
+fn push_attr(msg: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    msg.extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(payload);
+    msg.resize(align(msg.len()), 0);
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn ip_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+/// 拆出第一条 netlink 消息, 返回类型, 序号, 消息体和剩余部分
+fn split_message(buf: &[u8]) -> Option<(u16, u32, &[u8], &[u8])> {
+    let len = u32::from_ne_bytes(buf.get(0..4)?.try_into().unwrap()) as usize;
+    if len < NLMSG_HDR_LEN || len > buf.len() {
+        return None;
+    }
+    let kind = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+    let seq = u32::from_ne_bytes(buf[8..12].try_into().unwrap());
+    let next = buf.get(align(len)..).unwrap_or(&[]);
+    Some((kind, seq, &buf[NLMSG_HDR_LEN..len], next))
+}
+
+/// 解析 RTM_NEWROUTE 的消息体
+fn parse_route(body: &[u8]) -> Option<RouteEntry> {
+    let family = *body.first()? as i32;
+    let prefix_len = *body.get(1)?;
+    let mut dest = None;
+    let mut entry = RouteEntry {
+        dest: IpNet::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap(),
+        gateway: None,
+        oif: 0,
+        metric: None,
+    };
+    let mut attrs = body.get(RTMSG_LEN..)?;
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let kind = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        let payload = attrs.get(4..len)?;
+        match kind {
+            RTA_DST => dest = Some(parse_ip(family, payload)?),
+            RTA_GATEWAY => entry.gateway = Some(parse_ip(family, payload)?),
+            RTA_OIF => entry.oif = u32::from_ne_bytes(payload.try_into().ok()?),
+            RTA_PRIORITY => entry.metric = Some(u32::from_ne_bytes(payload.try_into().ok()?)),
+            _ => {}
+        }
+        attrs = attrs.get(align(len)..).unwrap_or(&[]);
+    }
+    let dest = dest.unwrap_or(match family {
+        libc::AF_INET6 => Ipv6Addr::UNSPECIFIED.into(),
+        _ => Ipv4Addr::UNSPECIFIED.into(),
+    });
+    entry.dest = IpNet::new(dest, prefix_len).ok()?;
+    Some(entry)
+}
+
+fn parse_ip(family: i32, payload: &[u8]) -> Option<IpAddr> {
+    match family {
+        libc::AF_INET => Some(Ipv4Addr::from(<[u8; 4]>::try_from(payload).ok()?).into()),
+        libc::AF_INET6 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(payload).ok()?).into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_parse_route() {
+        let route = RouteEntry {
+            dest: "10.0.0.0/8".parse().unwrap(),
+            gateway: Some("192.168.1.1".parse().unwrap()),
+            oif: 3,
+            metric: Some(50),
+        };
+        let msg = encode_route(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE, 7, &route);
+        // 头部 16 + rtmsg 12 + DST 8 + GATEWAY 8 + OIF 8 + PRIORITY 8
+        assert_eq!(msg.len(), 60);
+        let (kind, seq, body, rest) = split_message(&msg).unwrap();
+        assert_eq!((kind, seq, rest.len()), (RTM_NEWROUTE, 7, 0));
+        assert_eq!(body[..8], [libc::AF_INET as u8, 8, 0, 0, 254, 4, 0, 1]);
+        assert_eq!(parse_route(body).unwrap(), route);
+
+        let v6 = RouteEntry {
+            dest: "2001:db8::/32".parse().unwrap(),
+            gateway: None,
+            oif: 9,
+            metric: None,
+        };
+        let msg = encode_route(RTM_NEWROUTE, 0, 1, &v6);
+        let (_, _, body, _) = split_message(&msg).unwrap();
+        assert_eq!(body[6], RT_SCOPE_LINK);
+        assert_eq!(parse_route(body).unwrap(), v6);
+        assert!(split_message(&msg[..10]).is_none());
+    }
+
+    #[test]
+    fn test_get_route_to_loopback() {
+        let mut netlink = Netlink::open().unwrap();
+        let route = netlink.get_route("127.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(route.dest, "127.0.0.1/32".parse::<IpNet>().unwrap());
+        assert_eq!(route.gateway, None);
+        assert_eq!(if_name(route.oif).unwrap(), "lo");
+        assert_eq!(if_index("lo").unwrap(), route.oif);
+    }
+}
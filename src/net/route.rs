@@ -0,0 +1,393 @@
+use crate::gp::getconfig::GatewayConfig;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use pnet::datalink;
+use std::fmt;
+use std::net::IpAddr;
+
+/// 路由的去向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// 经过隧道网卡
+    Tunnel,
+    /// 沿用连接前到达该地址的路径, 用于排除的网段和网关自身
+    Direct,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub dest: IpNet,
+    pub target: Target,
+}
+
+/// 网关 getconfig 中与路由有关的设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutingPolicy {
+    /// 网关的公网地址, ESP 和 SSL 隧道的包必须绕开隧道
+    pub gateway: IpAddr,
+    /// access-routes 和 access-routes-v6
+    pub include: Vec<IpNet>,
+    /// exclude-access-routes 和 exclude-access-routes-v6
+    pub exclude: Vec<IpNet>,
+    /// no-direct-access-to-local-network, 本地网段也经过隧道
+    pub tunnel_local_networks: bool,
+}
+
+impl RoutingPolicy {
+    /// `gateway` 是客户端实际连接的网关公网地址 (例如 `tunnel::select::resolve_gateway` 的结果),
+    /// 不是 getconfig 中的 gw-address, 后者是网关在隧道内的地址
+    pub fn from_gateway(gateway: IpAddr, config: &GatewayConfig) -> Self {
+        RoutingPolicy {
+            gateway,
+            include: config
+                .access_routes
+                .iter()
+                .map(|&net| IpNet::V4(net))
+                .chain(config.access_routes_v6.iter().map(|&net| IpNet::V6(net)))
+                .collect(),
+            exclude: config
+                .exclude_access_routes
+                .iter()
+                .map(|&net| IpNet::V4(net))
+                .chain(
+                    config
+                        .exclude_access_routes_v6
+                        .iter()
+                        .map(|&net| IpNet::V6(net)),
+                )
+                .collect(),
+            tunnel_local_networks: config.no_direct_access_to_local_network,
+        }
+    }
+
+    /// 计算需要安装的路由, `local_networks` 是本机网卡直连的网段 (不含隧道网卡)
+    ///
+    /// 默认路由拆成两个 /1, 比原有的默认路由更具体, 不需要改动它;
+    /// 本地网段同样拆成两半, 才能盖过内核的直连路由.
+    pub fn plan(&self, local_networks: &[IpNet]) -> Vec<Route> {
+        let mut routes = vec![Route {
+            dest: IpNet::from(self.gateway),
+            target: Target::Direct,
+        }];
+        let mut push = |dest: IpNet, target: Target| {
+            if !routes.iter().any(|r| r.dest == dest) {
+                routes.push(Route { dest, target });
+            }
+        };
+
+        for net in &self.exclude {
+            push(net.trunc(), Target::Direct);
+        }
+        for net in &self.include {
+            for half in split_default(net.trunc()) {
+                push(half, Target::Tunnel);
+            }
+        }
+        if self.tunnel_local_networks {
+            for net in local_networks {
+                let net = net.trunc();
+                if net.prefix_len() == net.max_prefix_len() || self.exclude.contains(&net) {
+                    continue;
+                }
+                for half in net.subnets(net.prefix_len() + 1).unwrap() {
+                    push(half, Target::Tunnel);
+                }
+            }
+        }
+        routes
+    }
+}
+
+fn split_default(net: IpNet) -> Vec<IpNet> {
+    if net.prefix_len() != 0 {
+        return vec![net];
+    }
+    match net {
+        IpNet::V4(_) => ["0.0.0.0/1", "128.0.0.0/1"]
+            .map(|s| IpNet::V4(s.parse::<Ipv4Net>().unwrap()))
+            .to_vec(),
+        IpNet::V6(_) => ["::/1", "8000::/1"]
+            .map(|s| IpNet::V6(s.parse::<Ipv6Net>().unwrap()))
+            .to_vec(),
+    }
+}
+
+/// 本机除 `tunnel` 之外已启用网卡的直连网段
+pub fn local_networks(tunnel: &str) -> Vec<IpNet> {
+    datalink::interfaces()
+        .iter()
+        .filter(|iface| iface.is_up() && !iface.is_loopback() && iface.name != tunnel)
+        .flat_map(|iface| iface.ips.iter())
+        .filter(|ip| match ip.ip() {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .filter_map(|ip| IpNet::new(ip.ip(), ip.prefix()).ok())
+        .map(|net| net.trunc())
+        .collect()
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            Target::Tunnel => write!(f, "{} via tunnel", self.dest),
+            Target::Direct => write!(f, "{} direct", self.dest),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use self::linux::Router;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Route, Target};
+    use crate::net::netlink::{if_index, if_name, Netlink, RouteEntry};
+    use std::io;
+
+    /// 通过 netlink 安装路由, 记录安装过的每一条, 断开时按相反顺序删除
+    #[derive(Debug)]
+    pub struct Router {
+        netlink: Netlink,
+        tunnel: u32,
+        dry_run: bool,
+        installed: Vec<RouteEntry>,
+    }
+
+    impl Router {
+        /// `dry_run` 时只查询现有路由并打印计划的改动, 不修改系统
+        pub fn new(tunnel: &str, dry_run: bool) -> io::Result<Self> {
+            Ok(Router {
+                netlink: Netlink::open()?,
+                tunnel: if_index(tunnel)?,
+                dry_run,
+                installed: Vec::new(),
+            })
+        }
+
+        /// 已经安装 (dry-run 时为计划安装) 的路由
+        pub fn installed(&self) -> &[RouteEntry] {
+            &self.installed
+        }
+
+        /// 安装路由, 任意一条失败时撤销已经安装的部分
+        pub fn apply(&mut self, routes: &[Route]) -> io::Result<()> {
+            // 先查出直连路由的出口, 之后安装的隧道路由可能覆盖它们
+            let mut entries = Vec::new();
+            for route in routes {
+                let entry = match route.target {
+                    Target::Tunnel => RouteEntry {
+                        dest: route.dest,
+                        gateway: None,
+                        oif: self.tunnel,
+                        metric: None,
+                    },
+                    Target::Direct => {
+                        let mut entry = self.netlink.get_route(route.dest.network())?;
+                        entry.dest = route.dest;
+                        if entry.oif == self.tunnel {
+                            return Err(io::Error::other(format!(
+                                "{} is already routed through the tunnel",
+                                route.dest
+                            )));
+                        }
+                        entry
+                    }
+                };
+                entries.push(entry);
+            }
+
+            for entry in entries {
+                if self.dry_run {
+                    println!("ip route add {}", describe(&entry));
+                    self.installed.push(entry);
+                    continue;
+                }
+                match self.netlink.add_route(&entry) {
+                    Ok(()) => self.installed.push(entry),
+                    // 已有的路由不是我们添加的, 断开时也不删除
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        log::info!("route {} already exists", describe(&entry));
+                    }
+                    Err(e) => {
+                        let context = format!("failed to add route {}: {}", describe(&entry), e);
+                        if let Err(e) = self.rollback() {
+                            log::warn!("rollback after failed route failed: {}", e);
+                        }
+                        return Err(io::Error::new(e.kind(), context));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// 删除安装过的全部路由, 单条失败时继续删除其余的并返回第一个错误
+        pub fn rollback(&mut self) -> io::Result<()> {
+            let mut result = Ok(());
+            while let Some(entry) = self.installed.pop() {
+                if self.dry_run {
+                    println!("ip route del {}", describe(&entry));
+                    continue;
+                }
+                match self.netlink.del_route(&entry) {
+                    // 网卡消失时内核已经删掉了经过它的路由
+                    Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {}
+                    Err(e) => {
+                        log::warn!("failed to delete route {}: {}", describe(&entry), e);
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                    Ok(()) => {}
+                }
+            }
+            result
+        }
+    }
+
+    impl Drop for Router {
+        fn drop(&mut self) {
+            if let Err(e) = self.rollback() {
+                log::warn!("failed to restore routes: {}", e);
+            }
+        }
+    }
+
+    /// 与 `ip route` 相同的写法
+    fn describe(entry: &RouteEntry) -> String {
+        let mut text = entry.dest.to_string();
+        if let Some(gateway) = entry.gateway {
+            text.push_str(&format!(" via {}", gateway));
+        }
+        let dev = if_name(entry.oif).unwrap_or_else(|_| format!("if{}", entry.oif));
+        text.push_str(&format!(" dev {}", dev));
+        text
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_dry_run_does_not_touch_routes() {
+            let routes = [
+                Route {
+                    dest: "127.0.0.2/32".parse().unwrap(),
+                    target: Target::Direct,
+                },
+                Route {
+                    dest: "198.51.100.0/24".parse().unwrap(),
+                    target: Target::Tunnel,
+                },
+            ];
+            // 用回环网卡代替隧道, dry-run 不需要权限
+            let mut router = Router::new("lo", true).unwrap();
+            router.apply(&routes[1..]).unwrap();
+            assert_eq!(router.installed().len(), 1);
+            assert_eq!(describe(&router.installed()[0]), "198.51.100.0/24 dev lo");
+            // 直连路由的出口就是 "隧道" 网卡, 拒绝安装
+            assert!(router.apply(&routes[..1]).is_err());
+            router.rollback().unwrap();
+            assert!(router.installed().is_empty());
+        }
+
+        /// 需要 root 或 CAP_NET_ADMIN: cargo test -- --ignored test_apply_and_rollback
+        #[tokio::test]
+        #[ignore = "needs root or CAP_NET_ADMIN"]
+        async fn test_apply_and_rollback() {
+            use crate::tunnel::tun::{TunConfig, TunDevice};
+
+            let config = TunConfig {
+                name: "gpdroute0".to_string(),
+                address: "10.99.1.2".parse().unwrap(),
+                netmask: "255.255.255.0".parse().unwrap(),
+                mtu: 1400,
+            };
+            let device = TunDevice::create(&config).unwrap();
+            let routes = [Route {
+                dest: "198.18.0.0/15".parse().unwrap(),
+                target: Target::Tunnel,
+            }];
+            let mut netlink = Netlink::open().unwrap();
+            let probe = "198.18.1.1".parse().unwrap();
+
+            let mut router = Router::new("gpdroute0", false).unwrap();
+            router.apply(&routes).unwrap();
+            assert_eq!(netlink.get_route(probe).unwrap().oif, router.tunnel);
+            router.rollback().unwrap();
+            assert_ne!(
+                netlink.get_route(probe).map(|r| r.oif).ok(),
+                Some(router.tunnel)
+            );
+            device.close().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_policy_uses_public_gateway() {
+        let config =
+            crate::gp::getconfig::parse_response(crate::gp::getconfig::tests::GETCONFIG_XML)
+                .unwrap()
+                .gateway;
+        let public: IpAddr = "47.100.9.56".parse().unwrap();
+        let policy = RoutingPolicy::from_gateway(public, &config);
+        let routes = policy.plan(&[]);
+        assert_eq!(routes[0].to_string(), "47.100.9.56/32 direct");
+        // 隧道内的 gw-address 不应被固定在隧道外
+        assert!(!routes
+            .iter()
+            .any(|r| r.dest == IpNet::from(config.gw_address)));
+    }
+
+    #[test]
+    fn test_plan_routes() {
+        let policy = RoutingPolicy {
+            gateway: "47.100.9.56".parse().unwrap(),
+            include: nets(&["0.0.0.0/0", "10.0.0.0/8", "10.1.2.3/8", "2001:db8::/32"]),
+            exclude: nets(&["10.10.0.0/16", "203.0.113.0/24"]),
+            tunnel_local_networks: false,
+        };
+        let local = nets(&["192.168.1.0/24"]);
+        let routes: Vec<String> = policy.plan(&local).iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            routes,
+            [
+                "47.100.9.56/32 direct",
+                "10.10.0.0/16 direct",
+                "203.0.113.0/24 direct",
+                "0.0.0.0/1 via tunnel",
+                "128.0.0.0/1 via tunnel",
+                "10.0.0.0/8 via tunnel",
+                "2001:db8::/32 via tunnel",
+            ]
+        );
+
+        let policy = RoutingPolicy {
+            gateway: "2001:db8:ffff::1".parse().unwrap(),
+            include: nets(&["::/0"]),
+            exclude: nets(&["192.168.2.0/24"]),
+            tunnel_local_networks: true,
+        };
+        let local = nets(&["192.168.1.0/24", "192.168.2.0/24", "10.9.9.9/32"]);
+        let routes: Vec<String> = policy.plan(&local).iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            routes,
+            [
+                "2001:db8:ffff::1/128 direct",
+                "192.168.2.0/24 direct",
+                "::/1 via tunnel",
+                "8000::/1 via tunnel",
+                "192.168.1.0/25 via tunnel",
+                "192.168.1.128/25 via tunnel",
+            ]
+        );
+    }
+}
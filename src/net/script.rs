@@ -0,0 +1,330 @@
+use crate::gp::getconfig::GatewayConfig;
+use crate::tunnel::tun::DEFAULT_MTU;
+use ipnet::{Ipv4Net, Ipv6Net};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// 调用 vpnc-script 的时机, 对应环境变量 reason
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// 创建隧道网卡之前, 此时还没有网关配置
+    PreInit,
+    Connect,
+    Disconnect,
+    Reconnect,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Reason::PreInit => "pre-init",
+            Reason::Connect => "connect",
+            Reason::Disconnect => "disconnect",
+            Reason::Reconnect => "reconnect",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// 外部脚本和内置路由/DNS 配置的关系
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptMode {
+    /// 由脚本完成全部网络配置, 不再使用内置的路由和 DNS 处理
+    Replace,
+    /// 内置处理完成后再运行脚本
+    Alongside,
+}
+
+/// 兼容 openconnect vpnc-script 的外部脚本
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VpncScript {
+    path: PathBuf,
+    mode: ScriptMode,
+}
+
+impl VpncScript {
+    pub fn new(path: impl Into<PathBuf>, mode: ScriptMode) -> Self {
+        VpncScript {
+            path: path.into(),
+            mode,
+        }
+    }
+
+    pub fn mode(&self) -> ScriptMode {
+        self.mode
+    }
+
+    /// 是否还需要内置的路由和 DNS 处理
+    pub fn builtin_network(&self) -> bool {
+        self.mode == ScriptMode::Alongside
+    }
+
+    /// 运行脚本, 退出码非 0 时返回错误. `gateway` 是网关的公网地址, 见 [`environment`]
+    pub async fn run(
+        &self,
+        reason: Reason,
+        tundev: &str,
+        gateway: IpAddr,
+        config: Option<&GatewayConfig>,
+    ) -> io::Result<()> {
+        let status = Command::new(&self.path)
+            .envs(environment(reason, tundev, gateway, config))
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "{} {} failed: {}",
+                self.path.display(),
+                reason,
+                status
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// vpnc-script 的环境变量, pre-init 时没有网关配置, 只有 reason, TUNDEV 和 VPNGATEWAY.
+///
+/// vpnc-script 会给 VPNGATEWAY 添加经过原默认网关的主机路由, 所以 `gateway` 必须是客户端
+/// 实际连接的公网地址, 而不是 getconfig 中隧道内的 gw-address.
+pub fn environment(
+    reason: Reason,
+    tundev: &str,
+    gateway: IpAddr,
+    config: Option<&GatewayConfig>,
+) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("reason".to_string(), reason.to_string()),
+        ("TUNDEV".to_string(), tundev.to_string()),
+        ("VPNGATEWAY".to_string(), gateway.to_string()),
+    ];
+    let Some(config) = config else {
+        return env;
+    };
+    let mut set = |key: &str, value: String| env.push((key.to_string(), value));
+
+    set("INTERNAL_IP4_ADDRESS", config.ip_address.to_string());
+    set("INTERNAL_IP4_NETMASK", config.netmask.to_string());
+    if let Ok(net) = Ipv4Net::with_netmask(config.ip_address, config.netmask) {
+        set("INTERNAL_IP4_NETMASKLEN", net.prefix_len().to_string());
+        set("INTERNAL_IP4_NETADDR", net.network().to_string());
+    }
+    set(
+        "INTERNAL_IP4_MTU",
+        config.mtu.unwrap_or(DEFAULT_MTU).to_string(),
+    );
+    if let Some(address) = config.ip_address_v6 {
+        set("INTERNAL_IP6_ADDRESS", address.to_string());
+    }
+
+    // 网关的 dns-v6 里也可能是 IPv4 地址, 按地址类型归类
+    let (dns4, dns6): (Vec<IpAddr>, Vec<IpAddr>) = config
+        .dns
+        .iter()
+        .chain(&config.dns_v6)
+        .partition(|server| server.is_ipv4());
+    if !dns4.is_empty() {
+        set("INTERNAL_IP4_DNS", join(dedup(dns4)));
+    }
+    if !dns6.is_empty() {
+        set("INTERNAL_IP6_DNS", join(dedup(dns6)));
+    }
+    if !config.dns_suffix.is_empty() {
+        set("CISCO_DEF_DOMAIN", config.dns_suffix.join(" "));
+    }
+
+    // 没有分流路由时 vpnc-script 把全部流量走隧道; 某个地址族的路由里有 /0 时
+    // 就是全隧道, 该地址族不能输出 SPLIT_INC, 否则其余流量会绕过 VPN
+    if !config.access_routes.iter().any(|net| net.prefix_len() == 0) {
+        split_v4(&mut env, "CISCO_SPLIT_INC", &config.access_routes);
+    }
+    split_v4(&mut env, "CISCO_SPLIT_EXC", &config.exclude_access_routes);
+    if !config
+        .access_routes_v6
+        .iter()
+        .any(|net| net.prefix_len() == 0)
+    {
+        split_v6(&mut env, "CISCO_IPV6_SPLIT_INC", &config.access_routes_v6);
+    }
+    split_v6(
+        &mut env,
+        "CISCO_IPV6_SPLIT_EXC",
+        &config.exclude_access_routes_v6,
+    );
+    env
+}
+
+fn dedup(mut list: Vec<IpAddr>) -> Vec<IpAddr> {
+    let mut seen = Vec::new();
+    list.retain(|addr| {
+        let new = !seen.contains(addr);
+        seen.push(*addr);
+        new
+    });
+    list
+}
+
+fn join(list: Vec<IpAddr>) -> String {
+    let list: Vec<String> = list.iter().map(|addr| addr.to_string()).collect();
+    list.join(" ")
+}
+
+fn split_v4(env: &mut Vec<(String, String)>, prefix: &str, nets: &[Ipv4Net]) {
+    if nets.is_empty() {
+        return;
+    }
+    env.push((prefix.to_string(), nets.len().to_string()));
+    for (i, net) in nets.iter().enumerate() {
+        let key = |field: &str| format!("{}_{}_{}", prefix, i, field);
+        env.push((key("ADDR"), net.network().to_string()));
+        env.push((key("MASK"), net.netmask().to_string()));
+        env.push((key("MASKLEN"), net.prefix_len().to_string()));
+        env.push((key("PROTOCOL"), "0".to_string()));
+        env.push((key("SPORT"), "0".to_string()));
+        env.push((key("DPORT"), "0".to_string()));
+    }
+}
+
+fn split_v6(env: &mut Vec<(String, String)>, prefix: &str, nets: &[Ipv6Net]) {
+    if nets.is_empty() {
+        return;
+    }
+    env.push((prefix.to_string(), nets.len().to_string()));
+    for (i, net) in nets.iter().enumerate() {
+        let key = |field: &str| format!("{}_{}_{}", prefix, i, field);
+        env.push((key("ADDR"), net.network().to_string()));
+        env.push((key("MASKLEN"), net.prefix_len().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gp::getconfig::parse_response;
+    use crate::gp::getconfig::tests::GETCONFIG_XML;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// 网关的公网地址, 和 XML 里隧道内的 gw-address 不同
+    const PUBLIC: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 1));
+
+    /// 在共享的 getconfig 样例上改成分流配置
+    fn split_config() -> GatewayConfig {
+        let mut config = parse_response(GETCONFIG_XML).unwrap().gateway;
+        config.netmask = "255.255.255.0".parse().unwrap();
+        config.mtu = Some(1300);
+        config.dns_v6 = vec!["fd00::53".parse().unwrap()];
+        config.dns_suffix = vec!["corp.example.com".to_string(), "example.com".to_string()];
+        config.access_routes = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "172.16.5.4/32".parse().unwrap(),
+        ];
+        config.access_routes_v6 = vec!["fd00::/8".parse().unwrap()];
+        config.exclude_access_routes = vec!["10.10.0.0/16".parse().unwrap()];
+        config
+    }
+
+    #[test]
+    fn test_environment() {
+        let config = split_config();
+        let env: HashMap<String, String> =
+            environment(Reason::Connect, "gpd0", PUBLIC, Some(&config))
+                .into_iter()
+                .collect();
+        let get = |key: &str| env.get(key).map(String::as_str);
+
+        assert_eq!(get("reason"), Some("connect"));
+        assert_eq!(get("TUNDEV"), Some("gpd0"));
+        assert_eq!(get("VPNGATEWAY"), Some("198.51.100.1"));
+        assert_eq!(get("INTERNAL_IP4_ADDRESS"), Some("10.193.129.116"));
+        assert_eq!(get("INTERNAL_IP4_NETMASK"), Some("255.255.255.0"));
+        assert_eq!(get("INTERNAL_IP4_NETMASKLEN"), Some("24"));
+        assert_eq!(get("INTERNAL_IP4_NETADDR"), Some("10.193.129.0"));
+        assert_eq!(get("INTERNAL_IP4_MTU"), Some("1300"));
+        assert_eq!(get("INTERNAL_IP6_ADDRESS"), Some("fc00::1abb"));
+        assert_eq!(get("INTERNAL_IP4_DNS"), Some("10.12.255.254"));
+        assert_eq!(get("INTERNAL_IP6_DNS"), Some("fd00::53"));
+        assert_eq!(
+            get("CISCO_DEF_DOMAIN"),
+            Some("corp.example.com example.com")
+        );
+
+        assert_eq!(get("CISCO_SPLIT_INC"), Some("2"));
+        assert_eq!(get("CISCO_SPLIT_INC_0_ADDR"), Some("10.0.0.0"));
+        assert_eq!(get("CISCO_SPLIT_INC_0_MASK"), Some("255.0.0.0"));
+        assert_eq!(get("CISCO_SPLIT_INC_0_MASKLEN"), Some("8"));
+        assert_eq!(get("CISCO_SPLIT_INC_1_ADDR"), Some("172.16.5.4"));
+        assert_eq!(get("CISCO_SPLIT_INC_1_MASKLEN"), Some("32"));
+        assert_eq!(get("CISCO_SPLIT_EXC"), Some("1"));
+        assert_eq!(get("CISCO_SPLIT_EXC_0_ADDR"), Some("10.10.0.0"));
+        assert_eq!(get("CISCO_IPV6_SPLIT_INC"), Some("1"));
+        assert_eq!(get("CISCO_IPV6_SPLIT_INC_0_ADDR"), Some("fd00::"));
+
+        let env = environment(Reason::PreInit, "gpd0", PUBLIC, None);
+        assert_eq!(
+            env,
+            [
+                ("reason".to_string(), "pre-init".to_string()),
+                ("TUNDEV".to_string(), "gpd0".to_string()),
+                ("VPNGATEWAY".to_string(), "198.51.100.1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_tunnel_has_no_split_include() {
+        // 0.0.0.0/0 和 ::/0 之外还有具体路由, 仍然是全隧道
+        let mut config = parse_response(GETCONFIG_XML).unwrap().gateway;
+        config.exclude_access_routes = vec!["10.10.0.0/16".parse().unwrap()];
+        let env: HashMap<String, String> =
+            environment(Reason::Connect, "gpd0", PUBLIC, Some(&config))
+                .into_iter()
+                .collect();
+        assert_eq!(config.access_routes.len(), 2);
+        assert!(
+            !env.keys().any(|key| key.contains("SPLIT_INC")),
+            "{:?}",
+            env
+        );
+        assert_eq!(env.get("CISCO_SPLIT_EXC").map(String::as_str), Some("1"));
+        assert_eq!(
+            env.get("INTERNAL_IP4_MTU").map(String::as_str),
+            Some("1400")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_script() {
+        let dir = std::env::temp_dir().join(format!("gpconnect-script-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vpnc-script");
+        let output = dir.join("output");
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\necho \"$reason $TUNDEV $CISCO_SPLIT_INC\" > {}\n[ \"$reason\" != disconnect ]\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = split_config();
+        let script = VpncScript::new(&path, ScriptMode::Replace);
+        assert!(!script.builtin_network());
+        script
+            .run(Reason::Connect, "gpd0", PUBLIC, Some(&config))
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "connect gpd0 2\n");
+        assert!(script
+            .run(Reason::Disconnect, "gpd0", PUBLIC, Some(&config))
+            .await
+            .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
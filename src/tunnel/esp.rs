@@ -0,0 +1,91 @@
+use super::{Transport, TransportError};
+use crate::libs::esp::{EspError, ESP};
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use tokio::net::UdpSocket;
+
+/// ESP over UDP, 网关默认监听 4501 端口
+#[derive(Debug)]
+pub struct EspTransport {
+    socket: UdpSocket,
+    outbound: ESP,
+    inbound: ESP,
+}
+
+impl EspTransport {
+    /// `socket` 必须已经 connect 到网关
+    pub fn new(socket: UdpSocket, outbound: ESP, inbound: ESP) -> Self {
+        EspTransport {
+            socket,
+            outbound,
+            inbound,
+        }
+    }
+
+    /// 绑定一个临时端口并连接到网关
+    pub async fn connect(gateway: SocketAddr, outbound: ESP, inbound: ESP) -> io::Result<Self> {
+        let local: SocketAddr = match gateway {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(gateway).await?;
+        Ok(EspTransport::new(socket, outbound, inbound))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn outbound(&self) -> &ESP {
+        &self.outbound
+    }
+
+    pub fn inbound(&self) -> &ESP {
+        &self.inbound
+    }
+}
+
+impl Transport for EspTransport {
+    fn headroom(&self) -> usize {
+        self.outbound.headroom()
+    }
+
+    fn tailroom(&self) -> usize {
+        self.outbound.tailroom()
+    }
+
+    async fn send(&self, buf: &mut [u8], len: usize) -> Result<(), TransportError> {
+        let packet_len = self
+            .outbound
+            .encapsulate_in_place(buf, len)
+            .map_err(|e| match e {
+                EspError::SequenceExhausted => TransportError::Fatal(e.to_string()),
+                e => TransportError::Dropped(e.to_string()),
+            })?;
+        self.socket
+            .send(&buf[..packet_len])
+            .await
+            .map_err(socket_error)?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<Range<usize>, TransportError> {
+        let len = self.socket.recv(buf).await.map_err(socket_error)?;
+        self.inbound
+            .decapsulate_in_place(&mut buf[..len])
+            .map_err(|e| TransportError::Dropped(e.to_string()))
+    }
+}
+
+/// connect 过的 UDP socket 会在下一次收发时报告网关方向返回的 ICMP 错误,
+/// 例如端口不可达时的 ECONNREFUSED. 这些错误只说明某个包没有送达, 不应使隧道停止
+fn socket_error(e: io::Error) -> TransportError {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable => TransportError::Dropped(e.to_string()),
+        _ => TransportError::Io(e),
+    }
+}
@@ -0,0 +1,85 @@
+use super::PacketDevice;
+use std::io;
+use tokio::sync::{mpsc, Mutex};
+
+/// 内存中的 `PacketDevice`, 不需要 root 权限, 用于测试和嵌入
+///
+/// 写入 `MemoryPeer` 的包相当于本机协议栈发出的包, 隧道交给设备的包可以从 `MemoryPeer` 读出.
+#[derive(Debug)]
+pub struct MemoryDevice {
+    mtu: usize,
+    from_host: Mutex<mpsc::Receiver<Vec<u8>>>,
+    to_host: mpsc::Sender<Vec<u8>>,
+}
+
+/// `MemoryDevice` 的另一端, 扮演本机协议栈
+#[derive(Debug)]
+pub struct MemoryPeer {
+    to_device: mpsc::Sender<Vec<u8>>,
+    from_device: mpsc::Receiver<Vec<u8>>,
+}
+
+const QUEUE_LEN: usize = 256;
+
+impl MemoryDevice {
+    pub fn new(mtu: usize) -> (MemoryDevice, MemoryPeer) {
+        let (to_device, from_host) = mpsc::channel(QUEUE_LEN);
+        let (to_host, from_device) = mpsc::channel(QUEUE_LEN);
+        let device = MemoryDevice {
+            mtu,
+            from_host: Mutex::new(from_host),
+            to_host,
+        };
+        let peer = MemoryPeer {
+            to_device,
+            from_device,
+        };
+        (device, peer)
+    }
+}
+
+impl PacketDevice for MemoryDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .from_host
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        if packet.len() > buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("packet of {} bytes exceeds mtu {}", packet.len(), self.mtu),
+            ));
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.to_host
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+impl MemoryPeer {
+    /// 模拟本机协议栈发出一个包
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.to_device
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    /// 读取隧道交给本机的下一个包, 设备关闭后返回 None
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_device.recv().await
+    }
+}
@@ -0,0 +1,449 @@
+pub mod esp;
+pub mod memory;
+pub mod select;
+pub mod ssl;
+#[cfg(target_os = "linux")]
+pub mod tun;
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// 收发内层 IP 包的设备, 例如 TUN 网卡
+pub trait PacketDevice: Send + Sync {
+    /// 读取一个 IP 包到 `buf`, 返回包的长度
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+    /// 把一个 IP 包交给本机协议栈
+    fn send(&self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+    /// 设备能收发的最大 IP 包长度
+    fn mtu(&self) -> usize;
+}
+
+/// 和网关之间的封装通道, 例如 ESP over UDP
+///
+/// 发送时内层包已经放在缓冲区中间, 前后留出 `headroom` 和 `tailroom`,
+/// 实现可以原地封装而不用复制.
+pub trait Transport: Send + Sync {
+    /// 内层包之前需要预留的字节数
+    fn headroom(&self) -> usize;
+    /// 内层包之后需要预留的字节数
+    fn tailroom(&self) -> usize;
+    /// 封装并发送 `buf[headroom()..headroom() + len]` 中的内层包
+    fn send(
+        &self,
+        buf: &mut [u8],
+        len: usize,
+    ) -> impl Future<Output = Result<(), TransportError>> + Send;
+    /// 接收一个包并在 `buf` 中原地解开, 返回内层包的位置; 控制包由实现自己处理
+    fn recv(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<Range<usize>, TransportError>> + Send;
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// 单个包无法处理, 丢弃后继续转发
+    Dropped(String),
+    /// 通道不再可用, 例如序号用完需要重新协商密钥
+    Fatal(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Dropped(reason) => write!(f, "packet dropped: {}", reason),
+            TransportError::Fatal(reason) => write!(f, "transport failed: {}", reason),
+            TransportError::Io(e) => write!(f, "transport I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// 使隧道停止的错误
+#[derive(Debug)]
+pub enum TunnelError {
+    Device(io::Error),
+    Transport(TransportError),
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::Device(e) => write!(f, "packet device error: {}", e),
+            TunnelError::Transport(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TunnelError {}
+
+/// 一个方向上转发和丢弃的包数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectionStats {
+    pub packets: u64,
+    /// 内层 IP 包的字节数, 不含封装开销
+    pub bytes: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    /// 从设备发往网关
+    pub outbound: DirectionStats,
+    /// 从网关发往设备
+    pub inbound: DirectionStats,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    fn forwarded(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> DirectionStats {
+        DirectionStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 让正在运行的 `Tunnel::run` 返回, 可以在其他任务中调用
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// 数据通道: 在 `PacketDevice` 和 `Transport` 之间双向转发 IP 包,
+/// 直到被关闭或任意一侧出错
+#[derive(Debug)]
+pub struct Tunnel<D, T> {
+    device: D,
+    transport: T,
+    outbound: Counters,
+    inbound: Counters,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// 从网关收包的缓冲区大小, 能装下任意 UDP 数据报
+const RECV_BUFFER_LEN: usize = 65536;
+
+impl<D: PacketDevice, T: Transport> Tunnel<D, T> {
+    pub fn new(device: D, transport: T) -> Self {
+        Tunnel {
+            device,
+            transport,
+            outbound: Counters::default(),
+            inbound: Counters::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn stats(&self) -> TunnelStats {
+        TunnelStats {
+            outbound: self.outbound.snapshot(),
+            inbound: self.inbound.snapshot(),
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// 开始转发, 调用 `ShutdownHandle::shutdown` 后返回 Ok, 设备或通道出错时返回错误.
+    /// 单个无法封装或解开的包只计入 `dropped`, 不会使隧道停止.
+    pub async fn run(&self) -> Result<(), TunnelError> {
+        let mut shutdown = self.shutdown.subscribe();
+        if *shutdown.borrow_and_update() {
+            return Ok(());
+        }
+        tokio::select! {
+            result = self.pump_outbound() => result,
+            result = self.pump_inbound() => result,
+            // 发送端由 self 持有, 不会提前关闭
+            _ = shutdown.changed() => Ok(()),
+        }
+    }
+
+    async fn pump_outbound(&self) -> Result<(), TunnelError> {
+        let headroom = self.transport.headroom();
+        let mtu = self.device.mtu();
+        let mut buf = vec![0u8; headroom + mtu + self.transport.tailroom()];
+        loop {
+            let len = self
+                .device
+                .recv(&mut buf[headroom..headroom + mtu])
+                .await
+                .map_err(TunnelError::Device)?;
+            if len == 0 {
+                continue;
+            }
+            match self.transport.send(&mut buf, len).await {
+                Ok(()) => self.outbound.forwarded(len),
+                Err(TransportError::Dropped(reason)) => {
+                    log::debug!("dropped outbound packet: {}", reason);
+                    self.outbound.dropped();
+                }
+                Err(e) => return Err(TunnelError::Transport(e)),
+            }
+        }
+    }
+
+    async fn pump_inbound(&self) -> Result<(), TunnelError> {
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        loop {
+            match self.transport.recv(&mut buf).await {
+                Ok(packet) => {
+                    let len = packet.len();
+                    self.device
+                        .send(&buf[packet])
+                        .await
+                        .map_err(TunnelError::Device)?;
+                    self.inbound.forwarded(len);
+                }
+                Err(TransportError::Dropped(reason)) => {
+                    log::debug!("dropped inbound packet: {}", reason);
+                    self.inbound.dropped();
+                }
+                Err(e) => return Err(TunnelError::Transport(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::esp::EspTransport;
+    use super::memory::{MemoryDevice, MemoryPeer};
+    use super::*;
+    use crate::libs::esp::{EncAlgo, ESP};
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+    use tokio::time::{timeout, Duration};
+
+    fn sa(spi: u32, key: u8) -> ESP {
+        ESP::new(1, spi, EncAlgo::Aes128Gcm, None, &[key; 20], &[]).unwrap()
+    }
+
+    /// 在回环地址上用两条 ESP 隧道模拟客户端和网关
+    async fn tunnel_pair() -> (
+        Arc<Tunnel<MemoryDevice, EspTransport>>,
+        MemoryPeer,
+        Arc<Tunnel<MemoryDevice, EspTransport>>,
+        MemoryPeer,
+    ) {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket
+            .connect(gateway_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        gateway_socket
+            .connect(client_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (client_device, client_host) = MemoryDevice::new(1400);
+        let (gateway_device, gateway_host) = MemoryDevice::new(1400);
+        let client = Tunnel::new(
+            client_device,
+            EspTransport::new(client_socket, sa(0x1111, 1), sa(0x2222, 2)),
+        );
+        let gateway = Tunnel::new(
+            gateway_device,
+            EspTransport::new(gateway_socket, sa(0x2222, 2), sa(0x1111, 1)),
+        );
+        (
+            Arc::new(client),
+            client_host,
+            Arc::new(gateway),
+            gateway_host,
+        )
+    }
+
+    fn ipv4(tag: u8, len: usize) -> Vec<u8> {
+        let mut packet = vec![tag; len];
+        packet[0] = 0x45;
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_forwards_both_directions() {
+        let (client, mut client_host, gateway, mut gateway_host) = tunnel_pair().await;
+        let running = [client.clone(), gateway.clone()]
+            .map(|tunnel| tokio::spawn(async move { tunnel.run().await }));
+
+        for i in 0..10u8 {
+            client_host.send(&ipv4(i, 100)).await.unwrap();
+            let received = timeout(Duration::from_secs(5), gateway_host.recv()).await;
+            assert_eq!(received.unwrap().unwrap(), ipv4(i, 100));
+        }
+        gateway_host.send(&ipv4(0xee, 1400)).await.unwrap();
+        let received = timeout(Duration::from_secs(5), client_host.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), ipv4(0xee, 1400));
+
+        // 非 IP 包在出站方向丢弃, 隧道继续工作
+        client_host.send(&[0x10; 40]).await.unwrap();
+        client_host.send(&ipv4(0x42, 60)).await.unwrap();
+        let received = timeout(Duration::from_secs(5), gateway_host.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), ipv4(0x42, 60));
+
+        for tunnel in [&client, &gateway] {
+            tunnel.shutdown_handle().shutdown();
+        }
+        for handle in running {
+            timeout(Duration::from_secs(5), handle)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+
+        let stats = client.stats();
+        assert_eq!(
+            stats.outbound,
+            DirectionStats {
+                packets: 11,
+                bytes: 1060,
+                dropped: 1,
+            }
+        );
+        assert_eq!(
+            stats.inbound,
+            DirectionStats {
+                packets: 1,
+                bytes: 1400,
+                dropped: 0,
+            }
+        );
+        assert_eq!(gateway.stats().inbound.packets, 11);
+        assert_eq!(gateway.stats().outbound.bytes, 1400);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_drops_bad_inbound_packets() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport =
+            EspTransport::connect(gateway.local_addr().unwrap(), sa(0x1111, 1), sa(0x2222, 2))
+                .await
+                .unwrap();
+        let client_addr = ([127, 0, 0, 1], transport.local_addr().unwrap().port()).into();
+        gateway.connect::<SocketAddr>(client_addr).await.unwrap();
+        let (device, mut host) = MemoryDevice::new(1400);
+        let client = Arc::new(Tunnel::new(device, transport));
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.run().await }
+        });
+
+        // 截断的包, 错误的 SPI, 认证失败的包, 最后是一个正常的包
+        let espout = sa(0x2222, 2);
+        let valid = espout.encapsulate(&ipv4(7, 80)).unwrap();
+        let mut tampered = espout.encapsulate(&ipv4(8, 80)).unwrap();
+        tampered[20] ^= 1;
+        let wrong_spi = sa(0x3333, 2).encapsulate(&ipv4(9, 80)).unwrap();
+        for packet in [&valid[..10], &wrong_spi, &tampered, &valid] {
+            gateway.send(packet).await.unwrap();
+        }
+        let received = timeout(Duration::from_secs(5), host.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), ipv4(7, 80));
+
+        client.shutdown_handle().shutdown();
+        running.await.unwrap().unwrap();
+        assert_eq!(
+            client.stats().inbound,
+            DirectionStats {
+                packets: 1,
+                bytes: 80,
+                dropped: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_survives_icmp_unreachable() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = gateway.local_addr().unwrap();
+        let transport = EspTransport::connect(gateway_addr, sa(0x1111, 1), sa(0x2222, 2))
+            .await
+            .unwrap();
+        let client_addr: SocketAddr =
+            ([127, 0, 0, 1], transport.local_addr().unwrap().port()).into();
+        let (device, mut host) = MemoryDevice::new(1400);
+        let client = Arc::new(Tunnel::new(device, transport));
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.run().await }
+        });
+
+        // 网关端口关闭, 内核回复 ICMP 端口不可达, 客户端 socket 在下一次收发时报告 ECONNREFUSED
+        drop(gateway);
+        host.send(&ipv4(1, 80)).await.unwrap();
+        let start = tokio::time::Instant::now();
+        while client.stats().inbound.dropped + client.stats().outbound.dropped == 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(!running.is_finished());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // 网关恢复后隧道继续工作
+        let gateway = UdpSocket::bind(gateway_addr).await.unwrap();
+        let valid = sa(0x2222, 2).encapsulate(&ipv4(7, 80)).unwrap();
+        gateway.send_to(&valid, client_addr).await.unwrap();
+        let received = timeout(Duration::from_secs(5), host.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), ipv4(7, 80));
+
+        client.shutdown_handle().shutdown();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_stops_when_device_closes() {
+        let (client, client_host, _gateway, _gateway_host) = tunnel_pair().await;
+        drop(client_host);
+        let result = timeout(Duration::from_secs(5), client.run()).await.unwrap();
+        assert!(matches!(result, Err(TunnelError::Device(_))));
+
+        // 关闭之后再运行立即返回
+        let (client, _client_host, _gateway, _gateway_host) = tunnel_pair().await;
+        client.shutdown_handle().shutdown();
+        assert!(client.run().await.is_ok());
+    }
+}
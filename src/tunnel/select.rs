@@ -0,0 +1,474 @@
+use super::esp::EspTransport;
+use super::ssl::SslTransport;
+use super::{Transport, TransportError};
+use crate::gp::getconfig::{get_config, GatewayConfig};
+use crate::gp::http::HttpOptions;
+use crate::gp::identity::ClientIdentity;
+use crate::gp::login::AuthCookie;
+use crate::libs::esp::ESP;
+use crate::libs::gpst::{is_probe_reply, probe_packet};
+use crate::libs::tls::client_config;
+use reqwest::{Client, Url};
+use rustls::ClientConfig;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::{timeout_at, Instant};
+
+/// ESP 可达性检测的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeOptions {
+    /// 发送的探测包个数, 为 0 时不尝试 ESP, 直接使用 SSL 隧道
+    pub count: u32,
+    /// 相邻两个探测包的间隔
+    pub interval: Duration,
+    /// 最后一个探测包发出后等待回复的时间
+    pub timeout: Duration,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            count: 3,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// 选择某个通道的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectReason {
+    /// 收到了探测包的回复
+    EspReachable { probes: u32, elapsed: Duration },
+    /// 所有探测包都没有回复, UDP 可能被防火墙丢弃
+    NoProbeReply { probes: u32, waited: Duration },
+    /// 没有尝试 ESP
+    EspSkipped(String),
+    /// 无法建立 ESP 通道, 例如网关地址解析失败
+    EspFailed(String),
+}
+
+impl fmt::Display for SelectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectReason::EspReachable { probes, elapsed } => write!(
+                f,
+                "gateway answered ESP probes ({} sent, {:?})",
+                probes, elapsed
+            ),
+            SelectReason::NoProbeReply { probes, waited } => {
+                write!(f, "no reply to {} ESP probes within {:?}", probes, waited)
+            }
+            SelectReason::EspSkipped(reason) => write!(f, "ESP not attempted: {}", reason),
+            SelectReason::EspFailed(reason) => write!(f, "ESP failed: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Esp,
+    Ssl,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportKind::Esp => f.write_str("ESP"),
+            TransportKind::Ssl => f.write_str("SSL"),
+        }
+    }
+}
+
+/// 连接时选出的通道, 交给 `Tunnel` 使用
+#[derive(Debug)]
+pub enum GatewayTransport {
+    Esp(EspTransport),
+    Ssl(SslTransport),
+}
+
+impl GatewayTransport {
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            GatewayTransport::Esp(_) => TransportKind::Esp,
+            GatewayTransport::Ssl(_) => TransportKind::Ssl,
+        }
+    }
+}
+
+impl Transport for GatewayTransport {
+    fn headroom(&self) -> usize {
+        match self {
+            GatewayTransport::Esp(t) => t.headroom(),
+            GatewayTransport::Ssl(t) => t.headroom(),
+        }
+    }
+
+    fn tailroom(&self) -> usize {
+        match self {
+            GatewayTransport::Esp(t) => t.tailroom(),
+            GatewayTransport::Ssl(t) => t.tailroom(),
+        }
+    }
+
+    async fn send(&self, buf: &mut [u8], len: usize) -> Result<(), TransportError> {
+        match self {
+            GatewayTransport::Esp(t) => t.send(buf, len).await,
+            GatewayTransport::Ssl(t) => t.send(buf, len).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<Range<usize>, TransportError> {
+        match self {
+            GatewayTransport::Esp(t) => t.recv(buf).await,
+            GatewayTransport::Ssl(t) => t.recv(buf).await,
+        }
+    }
+}
+
+/// 选出的通道和选择它的原因
+#[derive(Debug)]
+pub struct Selection {
+    pub transport: GatewayTransport,
+    pub reason: SelectReason,
+}
+
+/// 网关 getconfig 下发的配置和选出的通道
+#[derive(Debug)]
+pub struct Connection {
+    pub config: GatewayConfig,
+    pub selection: Selection,
+}
+
+/// 建立到网关的连接: getconfig 获取隧道配置和 ESP 密钥, 再用探测包选出 ESP 或 SSL 通道.
+/// `client` 和 `http` 与 login 时相同, 会话 cookie 保存在 `http.cookies` 中
+pub async fn connect(
+    client: &Client,
+    http: &HttpOptions,
+    gateway: &str,
+    cookie: &AuthCookie,
+    identity: &ClientIdentity,
+    options: &ProbeOptions,
+) -> Result<Connection, Box<dyn std::error::Error>> {
+    let config = get_config(client, &http.cookies, gateway, cookie, identity).await?;
+    let tls = client_config(&http.verify, http.client_cert.as_ref())?;
+    let selection = select_transport(
+        gateway,
+        &config.gateway,
+        config.espout,
+        config.espin,
+        cookie,
+        tls,
+        options,
+    )
+    .await?;
+    Ok(Connection {
+        config: config.gateway,
+        selection,
+    })
+}
+
+/// 先用探测包检查 ESP 是否可用, 不可用时连接 SSL 隧道.
+/// `gateway` 是网关的 host 或 host:port, `tls` 通常来自 [`crate::libs::tls::client_config`].
+pub async fn select_transport(
+    gateway: &str,
+    config: &GatewayConfig,
+    espout: ESP,
+    espin: ESP,
+    cookie: &AuthCookie,
+    tls: Arc<ClientConfig>,
+    options: &ProbeOptions,
+) -> io::Result<Selection> {
+    let reason = match try_esp(gateway, config, espout, espin, options).await {
+        Ok((transport, reason)) => {
+            log::info!("using ESP transport: {}", reason);
+            return Ok(Selection {
+                transport: GatewayTransport::Esp(transport),
+                reason,
+            });
+        }
+        Err(reason) => reason,
+    };
+
+    let Some(url) = config.ssl_tunnel_url.as_deref() else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}, and the gateway offers no SSL tunnel", reason),
+        ));
+    };
+    log::warn!("falling back to the SSL tunnel: {}", reason);
+    let transport = SslTransport::connect(gateway, url, cookie, tls).await?;
+    Ok(Selection {
+        transport: GatewayTransport::Ssl(transport),
+        reason,
+    })
+}
+
+/// 解析网关的公网地址, `gateway` 是 host 或 host:port, 其中的端口会被 `port` 替换
+pub async fn resolve_gateway(gateway: &str, port: u16) -> io::Result<SocketAddr> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let url = Url::parse(&format!("https://{}", gateway)).map_err(|e| invalid(e.to_string()))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid(format!("no host in {}", gateway)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let mut addrs = lookup_host((host.as_str(), port)).await?;
+    addrs
+        .next()
+        .ok_or_else(|| invalid(format!("{} has no address", host)))
+}
+
+async fn try_esp(
+    gateway: &str,
+    config: &GatewayConfig,
+    espout: ESP,
+    espin: ESP,
+    options: &ProbeOptions,
+) -> Result<(EspTransport, SelectReason), SelectReason> {
+    if options.count == 0 {
+        return Err(SelectReason::EspSkipped("probing disabled".to_string()));
+    }
+    // 探测包是发往网关隧道内地址 gw-address 的 IPv4 ping, ESP 本身发往网关的公网地址
+    let IpAddr::V4(destination) = config.gw_address else {
+        return Err(SelectReason::EspSkipped(format!(
+            "gw-address {} is not IPv4",
+            config.gw_address
+        )));
+    };
+    let gateway = resolve_gateway(gateway, config.ipsec.udp_port)
+        .await
+        .map_err(|e| SelectReason::EspFailed(e.to_string()))?;
+    let transport = EspTransport::connect(gateway, espout, espin)
+        .await
+        .map_err(|e| SelectReason::EspFailed(e.to_string()))?;
+    match probe_esp(&transport, config.ip_address, destination, options).await {
+        Ok(Some((probes, elapsed))) => {
+            Ok((transport, SelectReason::EspReachable { probes, elapsed }))
+        }
+        Ok(None) => Err(SelectReason::NoProbeReply {
+            probes: options.count,
+            waited: options.interval * (options.count - 1) + options.timeout,
+        }),
+        Err(e) => Err(SelectReason::EspFailed(e.to_string())),
+    }
+}
+
+/// 每隔 `interval` 发送一个探测包, 直到收到回复或者超时;
+/// 返回收到回复前实际发出的探测包个数和距第一个探测包的时间
+pub async fn probe_esp(
+    transport: &EspTransport,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    options: &ProbeOptions,
+) -> io::Result<Option<(u32, Duration)>> {
+    let probe = probe_packet(source, destination);
+    let headroom = transport.headroom();
+    let mut out = vec![0u8; headroom + probe.len() + transport.tailroom()];
+    let mut buf = vec![0u8; 65536];
+
+    let start = Instant::now();
+    let deadline = start + options.interval * options.count.saturating_sub(1) + options.timeout;
+    let mut sent = 0;
+    let mut next_send = start;
+    loop {
+        if sent < options.count && Instant::now() >= next_send {
+            out[headroom..headroom + probe.len()].copy_from_slice(&probe);
+            transport.send(&mut out, probe.len()).await.map_err(to_io)?;
+            sent += 1;
+            next_send += options.interval;
+        }
+        let wake = match sent < options.count {
+            true => next_send.min(deadline),
+            false => deadline,
+        };
+        match timeout_at(wake, transport.recv(&mut buf)).await {
+            Ok(Ok(packet)) if is_probe_reply(&buf[packet.clone()]) => {
+                return Ok(Some((sent, start.elapsed())))
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(TransportError::Dropped(reason))) => {
+                log::debug!("ignored packet while probing ESP: {}", reason)
+            }
+            Ok(Err(e)) => return Err(to_io(e)),
+            Err(_) if Instant::now() >= deadline => return Ok(None),
+            Err(_) => {}
+        }
+    }
+}
+
+fn to_io(e: TransportError) -> io::Error {
+    match e {
+        TransportError::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libs::esp::EncAlgo;
+    use tokio::net::UdpSocket;
+
+    fn sa(spi: u32, key: u8) -> ESP {
+        ESP::new(1, spi, EncAlgo::Aes128Gcm, None, &[key; 20], &[]).unwrap()
+    }
+
+    const OPTIONS: ProbeOptions = ProbeOptions {
+        count: 2,
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(200),
+    };
+
+    async fn client_and_gateway() -> (EspTransport, UdpSocket) {
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport =
+            EspTransport::connect(gateway.local_addr().unwrap(), sa(0x1111, 1), sa(0x2222, 2))
+                .await
+                .unwrap();
+        (transport, gateway)
+    }
+
+    #[tokio::test]
+    async fn test_probe_reply() {
+        let (transport, gateway) = client_and_gateway().await;
+        let responder = tokio::spawn(async move {
+            let (inbound, outbound) = (sa(0x1111, 1), sa(0x2222, 2));
+            let mut buf = [0u8; 2048];
+            let (len, client) = gateway.recv_from(&mut buf).await.unwrap();
+            let mut probe = inbound.decapsulate(&buf[..len]).unwrap();
+            // 交换源地址和目的地址, 类型改为 echo reply
+            let (source, destination) = (probe[12..16].to_vec(), probe[16..20].to_vec());
+            probe[12..16].copy_from_slice(&destination);
+            probe[16..20].copy_from_slice(&source);
+            probe[20] = 0;
+            let reply = outbound.encapsulate(&probe).unwrap();
+            gateway.send_to(&reply, client).await.unwrap();
+        });
+
+        let result = probe_esp(
+            &transport,
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            &OPTIONS,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, Some((1, _))), "{:?}", result);
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_select_reports_probes_sent() {
+        use crate::gp::getconfig::{parse_response, tests::GETCONFIG_XML};
+        use crate::libs::tls::TlsVerify;
+
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = parse_response(GETCONFIG_XML).unwrap();
+        let mut gw = config.gateway;
+        gw.ipsec.udp_port = gateway.local_addr().unwrap().port();
+        let ipsec = &gw.ipsec;
+        let sa = |spi, key: &[u8], mac: &[u8]| {
+            ESP::new(1, spi, ipsec.enc_algo, ipsec.hmac_algo, key, mac).unwrap()
+        };
+        // 网关一侧: 收 c2s, 发 s2c
+        let inbound = sa(ipsec.c2s_spi, &ipsec.ekey_c2s, &ipsec.akey_c2s);
+        let outbound = sa(ipsec.s2c_spi, &ipsec.ekey_s2c, &ipsec.akey_s2c);
+
+        // 只回复第二个探测包
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            gateway.recv_from(&mut buf).await.unwrap();
+            let (len, client) = gateway.recv_from(&mut buf).await.unwrap();
+            let mut probe = inbound.decapsulate(&buf[..len]).unwrap();
+            let (source, destination) = (probe[12..16].to_vec(), probe[16..20].to_vec());
+            probe[12..16].copy_from_slice(&destination);
+            probe[16..20].copy_from_slice(&source);
+            probe[20] = 0;
+            let reply = outbound.encapsulate(&probe).unwrap();
+            gateway.send_to(&reply, client).await.unwrap();
+        });
+
+        let cookie = AuthCookie {
+            authcookie: "cookie".to_string(),
+            portal: "GP-GW".to_string(),
+            user: "alice".to_string(),
+            domain: None,
+            preferred_ip: None,
+            preferred_ipv6: None,
+            connection_type: None,
+        };
+        let tls = client_config(&TlsVerify::default(), None).unwrap();
+        let options = ProbeOptions {
+            count: 3,
+            ..OPTIONS
+        };
+        let selection = select_transport(
+            "127.0.0.1",
+            &gw,
+            config.espout,
+            config.espin,
+            &cookie,
+            tls,
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(selection.transport.kind(), TransportKind::Esp);
+        assert!(
+            matches!(
+                selection.reason,
+                SelectReason::EspReachable { probes: 2, .. }
+            ),
+            "{}",
+            selection.reason
+        );
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe_timeout() {
+        let (transport, gateway) = client_and_gateway().await;
+        let result = probe_esp(
+            &transport,
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            &OPTIONS,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result, None);
+
+        // 两个探测包都发出去了
+        let mut buf = [0u8; 2048];
+        for _ in 0..OPTIONS.count {
+            let len = gateway.recv(&mut buf).await.unwrap();
+            assert!(sa(0x1111, 1).decapsulate(&buf[..len]).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_gateway() {
+        let resolved = resolve_gateway("127.0.0.1:443", 4501).await.unwrap();
+        assert_eq!(resolved, "127.0.0.1:4501".parse().unwrap());
+        let resolved = resolve_gateway("[::1]", 4501).await.unwrap();
+        assert_eq!(resolved, "[::1]:4501".parse().unwrap());
+        assert!(resolve_gateway("", 4501).await.is_err());
+    }
+
+    #[test]
+    fn test_reason_display() {
+        let reason = SelectReason::NoProbeReply {
+            probes: 3,
+            waited: Duration::from_secs(5),
+        };
+        assert_eq!(reason.to_string(), "no reply to 3 ESP probes within 5s");
+        assert_eq!(TransportKind::Ssl.to_string(), "SSL");
+    }
+}
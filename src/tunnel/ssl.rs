@@ -0,0 +1,347 @@
+use super::{Transport, TransportError};
+use crate::gp::login::AuthCookie;
+use crate::libs::esp::NextHeader;
+use reqwest::Url;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// GPST 帧头: magic, ethertype, 长度, 类型, 保留字段
+pub const HEADER_LEN: usize = 16;
+const MAGIC: [u8; 4] = [0x1a, 0x2b, 0x3c, 0x4d];
+const TYPE_DATA: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+const TYPE_KEEPALIVE: [u8; 4] = [0; 4];
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// 网关接受隧道请求后的应答
+const START_TUNNEL: &[u8] = b"START_TUNNEL";
+
+/// 握手失败时读取错误信息的等待时间
+const ERROR_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 没有数据时也定期发送 keepalive, 避免网关和中间设备断开空闲连接
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// GPST 隧道: IP 包加上 16 字节帧头后通过 HTTPS 连接传输, UDP 被阻断时使用
+#[derive(Debug)]
+pub struct SslTransport<S = TlsStream<TcpStream>> {
+    reader: Mutex<ReadHalf<S>>,
+    writer: Arc<Mutex<WriteHalf<S>>>,
+    keepalive: JoinHandle<()>,
+}
+
+impl SslTransport {
+    /// 连接 `gateway` (host 或 host:port) 的 ssl-tunnel-url 并完成 START_TUNNEL 握手,
+    /// `tls` 通常来自 [`crate::libs::tls::client_config`]
+    pub async fn connect(
+        gateway: &str,
+        tunnel_url: &str,
+        cookie: &AuthCookie,
+        tls: Arc<ClientConfig>,
+    ) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mut url =
+            Url::parse(&format!("https://{}", gateway)).map_err(|e| invalid(e.to_string()))?;
+        url.set_path(tunnel_url);
+        url.query_pairs_mut()
+            .append_pair("user", &cookie.user)
+            .append_pair("authcookie", &cookie.authcookie);
+        let host = url
+            .host_str()
+            .ok_or_else(|| invalid(format!("no host in {}", gateway)))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let tcp = TcpStream::connect((host.as_str(), port)).await?;
+        tcp.set_nodelay(true)?;
+        let name = ServerName::try_from(host).map_err(|e| invalid(e.to_string()))?;
+        let stream = TlsConnector::from(tls).connect(name, tcp).await?;
+
+        let target = format!("{}?{}", url.path(), url.query().unwrap_or_default());
+        SslTransport::handshake(stream, &target).await
+    }
+}
+
+impl<S> SslTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    /// 在已经建立的连接上发送隧道请求, `target` 是带 user 和 authcookie 参数的请求路径.
+    /// 需要在 tokio 运行时中调用, 会启动发送 keepalive 的任务.
+    pub async fn handshake(mut stream: S, target: &str) -> io::Result<Self>
+    where
+        S: Unpin,
+    {
+        let request = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut reply = [0u8; START_TUNNEL.len()];
+        let mut read = 0;
+        while read < reply.len() {
+            match stream.read(&mut reply[read..]).await? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        if reply[..read] != *START_TUNNEL {
+            // 失败时网关通常返回一个 HTTP 错误页, 只取第一行; 连接可能不会关闭, 不等待 EOF
+            let mut text = reply[..read].to_vec();
+            let mut rest = [0u8; 512];
+            if let Ok(Ok(n)) =
+                tokio::time::timeout(ERROR_READ_TIMEOUT, stream.read(&mut rest)).await
+            {
+                text.extend_from_slice(&rest[..n]);
+            }
+            let text = String::from_utf8_lossy(&text);
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "gateway rejected the tunnel: {:?}",
+                    text.lines().next().unwrap_or_default()
+                ),
+            ));
+        }
+
+        let (reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(Mutex::new(writer));
+        let keepalive = tokio::spawn(send_keepalives(writer.clone()));
+        Ok(SslTransport {
+            reader: Mutex::new(reader),
+            writer,
+            keepalive,
+        })
+    }
+}
+
+impl<S> Drop for SslTransport<S> {
+    fn drop(&mut self) {
+        self.keepalive.abort();
+    }
+}
+
+async fn send_keepalives<W: AsyncWrite + Unpin>(writer: Arc<Mutex<W>>) {
+    let mut frame = [0u8; HEADER_LEN];
+    write_header(&mut frame, 0, 0, TYPE_KEEPALIVE);
+    let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let mut writer = writer.lock().await;
+        // 写失败时连接已经断开, 由收发路径报告错误
+        if write_frame(&mut *writer, &frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+fn write_header(header: &mut [u8], ethertype: u16, len: u16, kind: [u8; 4]) {
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&ethertype.to_be_bytes());
+    header[6..8].copy_from_slice(&len.to_be_bytes());
+    header[8..12].copy_from_slice(&kind);
+    header[12..16].fill(0);
+}
+
+impl<S> Transport for SslTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    fn headroom(&self) -> usize {
+        HEADER_LEN
+    }
+
+    fn tailroom(&self) -> usize {
+        0
+    }
+
+    async fn send(&self, buf: &mut [u8], len: usize) -> Result<(), TransportError> {
+        let ethertype = match NextHeader::of_packet(&buf[HEADER_LEN..HEADER_LEN + len]) {
+            Some(NextHeader::Ipv4) => ETHERTYPE_IPV4,
+            Some(NextHeader::Ipv6) => ETHERTYPE_IPV6,
+            None => return Err(TransportError::Dropped("not an IP packet".to_string())),
+        };
+        let frame_len = u16::try_from(len)
+            .map_err(|_| TransportError::Dropped(format!("packet too large: {}", len)))?;
+        write_header(&mut buf[..HEADER_LEN], ethertype, frame_len, TYPE_DATA);
+        let mut writer = self.writer.lock().await;
+        write_frame(&mut *writer, &buf[..HEADER_LEN + len]).await?;
+        Ok(())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> Result<Range<usize>, TransportError> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            reader.read_exact(&mut buf[..HEADER_LEN]).await?;
+            // 流式连接一旦失去帧同步就无法恢复
+            if buf[..4] != MAGIC {
+                return Err(TransportError::Fatal(format!(
+                    "bad GPST frame magic {:02x?}",
+                    &buf[..4]
+                )));
+            }
+            let ethertype = u16::from_be_bytes([buf[4], buf[5]]);
+            let len = u16::from_be_bytes([buf[6], buf[7]]) as usize;
+            if HEADER_LEN + len > buf.len() {
+                return Err(TransportError::Fatal(format!(
+                    "GPST frame of {} bytes does not fit in {}",
+                    len,
+                    buf.len() - HEADER_LEN
+                )));
+            }
+            reader
+                .read_exact(&mut buf[HEADER_LEN..HEADER_LEN + len])
+                .await?;
+            if buf[8..12] == TYPE_KEEPALIVE && len == 0 {
+                continue;
+            }
+            return match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Ok(HEADER_LEN..HEADER_LEN + len),
+                _ => Err(TransportError::Dropped(format!(
+                    "unknown GPST ethertype {:#06x}",
+                    ethertype
+                ))),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::memory::MemoryDevice;
+    use super::super::Tunnel;
+    use super::*;
+    use tokio::io::DuplexStream;
+    use tokio::time::timeout;
+
+    const TARGET: &str = "/ssl-tunnel-connect.sslvpn?user=alice&authcookie=abc%2B1";
+
+    /// 模拟网关读取隧道请求并应答
+    async fn gateway(reply: &[u8]) -> (DuplexStream, tokio::task::JoinHandle<DuplexStream>) {
+        let (client, mut server) = tokio::io::duplex(65536);
+        let reply = reply.to_vec();
+        let task = tokio::spawn(async move {
+            let mut request = vec![0u8; 1024];
+            let len = server.read(&mut request).await.unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&request[..len]),
+                format!("GET {} HTTP/1.1\r\n\r\n", TARGET)
+            );
+            server.write_all(&reply).await.unwrap();
+            server
+        });
+        (client, task)
+    }
+
+    fn frame(ethertype: u16, kind: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; HEADER_LEN];
+        write_header(&mut frame, ethertype, payload.len() as u16, kind);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_data_frames() {
+        let (client, task) = gateway(START_TUNNEL).await;
+        let transport = SslTransport::handshake(client, TARGET).await.unwrap();
+        let mut server = task.await.unwrap();
+
+        let packet = [0x45, 0, 0, 20, 1, 2, 3, 4];
+        let mut buf = vec![0u8; HEADER_LEN + packet.len()];
+        buf[HEADER_LEN..].copy_from_slice(&packet);
+        transport.send(&mut buf, packet.len()).await.unwrap();
+        let mut sent = vec![0u8; HEADER_LEN + packet.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(
+            hex::encode(&sent[..HEADER_LEN]),
+            "1a2b3c4d080000080100000000000000"
+        );
+        assert_eq!(&sent[HEADER_LEN..], &packet);
+
+        // keepalive 被跳过, 未知 ethertype 丢弃, 之后的包照常收到
+        let reply = [0x60, 0, 0, 0];
+        server
+            .write_all(&frame(0, TYPE_KEEPALIVE, &[]))
+            .await
+            .unwrap();
+        server
+            .write_all(&frame(0x0806, TYPE_DATA, &[1, 2]))
+            .await
+            .unwrap();
+        server
+            .write_all(&frame(ETHERTYPE_IPV6, TYPE_DATA, &reply))
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 2048];
+        assert!(matches!(
+            transport.recv(&mut buf).await,
+            Err(TransportError::Dropped(_))
+        ));
+        let range = transport.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[range], &reply);
+
+        server.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        assert!(matches!(
+            transport.recv(&mut buf).await,
+            Err(TransportError::Fatal(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_tunnel() {
+        let (client, _task) =
+            gateway(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await;
+        let err = SslTransport::handshake(client, TARGET).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().contains("502 Bad Gateway"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_over_ssl() {
+        let (client, task) = gateway(START_TUNNEL).await;
+        let transport = SslTransport::handshake(client, TARGET).await.unwrap();
+        let mut server = task.await.unwrap();
+        let (device, mut host) = MemoryDevice::new(1400);
+        let tunnel = Arc::new(Tunnel::new(device, transport));
+        let running = tokio::spawn({
+            let tunnel = tunnel.clone();
+            async move { tunnel.run().await }
+        });
+
+        let outbound = [0x45; 60];
+        host.send(&outbound).await.unwrap();
+        let mut sent = vec![0u8; HEADER_LEN + outbound.len()];
+        server.read_exact(&mut sent).await.unwrap();
+        assert_eq!(sent, frame(ETHERTYPE_IPV4, TYPE_DATA, &outbound));
+
+        let inbound = [0x45; 40];
+        server
+            .write_all(&frame(ETHERTYPE_IPV4, TYPE_DATA, &inbound))
+            .await
+            .unwrap();
+        let received = timeout(Duration::from_secs(5), host.recv()).await;
+        assert_eq!(received.unwrap().unwrap(), inbound);
+
+        // 网关关闭连接后隧道停止
+        drop(server);
+        let result = timeout(Duration::from_secs(5), running).await.unwrap();
+        assert!(result.unwrap().is_err());
+    }
+}
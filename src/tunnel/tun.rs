@@ -0,0 +1,302 @@
+use super::PacketDevice;
+use crate::gp::getconfig::GatewayConfig;
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// 网关没有下发 MTU 时使用的值, 与官方客户端一致
+pub const DEFAULT_MTU: u16 = 1400;
+
+/// tun 网卡的名字和地址, 地址部分来自网关 getconfig
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunConfig {
+    /// 为空时由内核分配 tunN
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub mtu: u16,
+}
+
+impl TunConfig {
+    pub fn from_gateway(name: &str, config: &GatewayConfig) -> Self {
+        TunConfig {
+            name: name.to_string(),
+            address: config.ip_address,
+            netmask: config.netmask,
+            mtu: config.mtu.unwrap_or(DEFAULT_MTU),
+        }
+    }
+}
+
+/// Linux 的 tun 网卡, 收发不带包头信息的 IP 包, 需要在 tokio 运行时中创建
+#[derive(Debug)]
+pub struct TunDevice {
+    fd: AsyncFd<OwnedFd>,
+    name: String,
+    mtu: usize,
+    /// 网卡由自己创建和配置, 退出时需要关闭
+    owned: bool,
+}
+
+impl TunDevice {
+    /// 创建 tun 网卡, 设置地址, 掩码和 MTU 后启用, 需要 CAP_NET_ADMIN
+    pub fn create(config: &TunConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+        let fd = OwnedFd::from(file);
+
+        let mut req = ifreq(&config.name)?;
+        req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req)?;
+        let name = ifreq_name(&req);
+
+        let device = TunDevice {
+            fd: AsyncFd::with_interest(fd, Interest::READABLE | Interest::WRITABLE)?,
+            name,
+            mtu: config.mtu as usize,
+            owned: true,
+        };
+        // 配置失败时由 Drop 关闭网卡
+        device.configure(config)?;
+        Ok(device)
+    }
+
+    /// 使用特权进程创建并配置好的 tun fd, 本进程不需要任何权限, 也不会修改网卡配置
+    pub fn from_fd(fd: OwnedFd, mtu: u16) -> io::Result<Self> {
+        let mut req: libc::ifreq = unsafe { mem::zeroed() };
+        ioctl(fd.as_raw_fd(), libc::TUNGETIFF, &mut req)?;
+        let flags = unsafe { req.ifr_ifru.ifru_flags } as libc::c_int;
+        if flags & libc::IFF_TUN == 0 || flags & libc::IFF_NO_PI == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file descriptor is not a tun device opened with IFF_NO_PI",
+            ));
+        }
+        set_nonblocking(fd.as_raw_fd())?;
+
+        Ok(TunDevice {
+            name: ifreq_name(&req),
+            fd: AsyncFd::with_interest(fd, Interest::READABLE | Interest::WRITABLE)?,
+            mtu: mtu as usize,
+            owned: false,
+        })
+    }
+
+    /// 从继承的 fd 编号创建, 例如 `--tun-fd 3`
+    ///
+    /// # Safety
+    /// `fd` 必须是本进程拥有且没有其他地方会关闭的 fd.
+    pub unsafe fn from_raw_fd(fd: RawFd, mtu: u16) -> io::Result<Self> {
+        TunDevice::from_fd(OwnedFd::from_raw_fd(fd), mtu)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn configure(&self, config: &TunConfig) -> io::Result<()> {
+        let socket = control_socket()?;
+        let fd = socket.as_raw_fd();
+
+        let mut req = ifreq(&self.name)?;
+        req.ifr_ifru.ifru_addr = sockaddr(config.address);
+        ioctl(fd, libc::SIOCSIFADDR, &mut req)?;
+
+        let mut req = ifreq(&self.name)?;
+        req.ifr_ifru.ifru_netmask = sockaddr(config.netmask);
+        ioctl(fd, libc::SIOCSIFNETMASK, &mut req)?;
+
+        let mut req = ifreq(&self.name)?;
+        req.ifr_ifru.ifru_mtu = config.mtu as libc::c_int;
+        ioctl(fd, libc::SIOCSIFMTU, &mut req)?;
+
+        self.set_up(fd, true)
+    }
+
+    fn set_up(&self, socket: RawFd, up: bool) -> io::Result<()> {
+        let mut req = ifreq(&self.name)?;
+        ioctl(socket, libc::SIOCGIFFLAGS, &mut req)?;
+        let flags = unsafe { req.ifr_ifru.ifru_flags };
+        let change = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        req.ifr_ifru.ifru_flags = match up {
+            true => flags | change,
+            false => flags & !change,
+        };
+        ioctl(socket, libc::SIOCSIFFLAGS, &mut req)
+    }
+
+    /// 停用网卡并关闭 fd, 非持久的 tun 网卡随 fd 关闭而删除.
+    /// 外部传入的 fd 只关闭, 网卡由创建它的进程处理
+    pub fn close(mut self) -> io::Result<()> {
+        self.teardown()
+    }
+
+    fn teardown(&mut self) -> io::Result<()> {
+        if !mem::take(&mut self.owned) {
+            return Ok(());
+        }
+        let socket = control_socket()?;
+        self.set_up(socket.as_raw_fd(), false)
+    }
+}
+
+impl Drop for TunDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.teardown() {
+            log::warn!("failed to bring down {}: {}", self.name, e);
+        }
+    }
+}
+
+impl PacketDevice for TunDevice {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd
+            .async_io(Interest::READABLE, |fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                cvt(n)
+            })
+            .await
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let n = self
+            .fd
+            .async_io(Interest::WRITABLE, |fd| {
+                let n =
+                    unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+                cvt(n)
+            })
+            .await?;
+        if n != packet.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short write to tun: {} of {} bytes", n, packet.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+fn cvt(n: libc::ssize_t) -> io::Result<usize> {
+    match n {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    // 名字以 NUL 结尾, 最多 15 个字符
+    if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name: {:?}", name),
+        ));
+    }
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(req)
+}
+
+fn ifreq_name(req: &libc::ifreq) -> String {
+    unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(addr).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) }
+}
+
+/// 用于 SIOCSIF* 的 socket
+fn control_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// request 的类型随 C 库不同, glibc 是 c_ulong, musl 是 c_int
+fn ioctl(fd: RawFd, request: libc::Ioctl, req: &mut libc::ifreq) -> io::Result<()> {
+    match unsafe { libc::ioctl(fd, request, req as *mut libc::ifreq) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ifreq_name() {
+        let req = ifreq("gpd0").unwrap();
+        assert_eq!(ifreq_name(&req), "gpd0");
+        assert!(ifreq("a-very-long-name").is_err());
+        assert!(ifreq("").is_ok());
+
+        let addr = sockaddr(Ipv4Addr::new(10, 193, 129, 116));
+        let sin = unsafe { mem::transmute::<libc::sockaddr, libc::sockaddr_in>(addr) };
+        assert_eq!(sin.sin_family, libc::AF_INET as libc::sa_family_t);
+        assert_eq!(sin.sin_addr.s_addr.to_ne_bytes(), [10, 193, 129, 116]);
+    }
+
+    #[tokio::test]
+    async fn test_from_fd_rejects_non_tun() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(TunDevice::from_fd(OwnedFd::from(file), 1400).is_err());
+    }
+
+    /// 需要 root 或 CAP_NET_ADMIN: cargo test -- --ignored test_create_and_pass_fd
+    #[tokio::test]
+    #[ignore = "needs root or CAP_NET_ADMIN"]
+    async fn test_create_and_pass_fd() {
+        let config = TunConfig {
+            name: "gpdtest0".to_string(),
+            address: Ipv4Addr::new(10, 99, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            mtu: 1300,
+        };
+        let device = TunDevice::create(&config).unwrap();
+        assert_eq!(device.name(), "gpdtest0");
+
+        // 模拟特权进程把 fd 交给非特权进程
+        let fd = device.fd.get_ref().try_clone().unwrap();
+        let passed = TunDevice::from_fd(fd, 1300).unwrap();
+        assert_eq!(passed.name(), "gpdtest0");
+        assert_eq!(passed.mtu(), 1300);
+        passed.close().unwrap();
+        device.close().unwrap();
+    }
+}